@@ -0,0 +1,120 @@
+use crate::renderer::{lookat, matrix_transform, orthographic, perspective};
+use crate::{v3, SquareMatrix, M4, V3};
+
+#[derive(Copy, Clone, Debug)]
+pub enum Projection {
+    Perspective {
+        fov_y: f64,
+        aspect: f64,
+        near: f64,
+        far: f64,
+    },
+    Orthographic {
+        left: f64,
+        right: f64,
+        bottom: f64,
+        top: f64,
+        near: f64,
+        far: f64,
+    },
+}
+
+impl Projection {
+    pub fn near_far(&self) -> (f64, f64) {
+        match *self {
+            Projection::Perspective { near, far, .. }
+            | Projection::Orthographic { near, far, .. } => (near, far),
+        }
+    }
+
+    // The same projection between other near and far planes
+    pub fn with_near_far(&self, near: f64, far: f64) -> Projection {
+        match *self {
+            Projection::Perspective { fov_y, aspect, .. } => Projection::Perspective {
+                fov_y,
+                aspect,
+                near,
+                far,
+            },
+            Projection::Orthographic {
+                left,
+                right,
+                bottom,
+                top,
+                ..
+            } => Projection::Orthographic {
+                left,
+                right,
+                bottom,
+                top,
+                near,
+                far,
+            },
+        }
+    }
+
+    pub fn matrix(&self) -> M4 {
+        match *self {
+            Projection::Perspective {
+                fov_y,
+                aspect,
+                near,
+                far,
+            } => perspective(fov_y, aspect, near, far),
+            Projection::Orthographic {
+                left,
+                right,
+                bottom,
+                top,
+                near,
+                far,
+            } => orthographic(left, right, bottom, top, near, far),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Camera {
+    pub eye: V3,
+    pub target: V3,
+    pub up: V3,
+    pub projection: Projection,
+}
+
+impl Camera {
+    pub fn new(eye: V3, target: V3, up: V3, projection: Projection) -> Camera {
+        Camera {
+            eye,
+            target,
+            up,
+            projection,
+        }
+    }
+
+    pub fn view_matrix(&self) -> M4 {
+        lookat(self.eye, self.target, self.up)
+    }
+
+    pub fn projection_matrix(&self) -> M4 {
+        self.projection.matrix()
+    }
+
+    // The world space corners of the part of the view volume between the
+    // near and far distances
+    pub fn frustum_corners(&self, near: f64, far: f64) -> [V3; 8] {
+        let m = self.projection.with_near_far(near, far).matrix() * self.view_matrix();
+        let inverse = m.invert().unwrap_or_else(M4::identity);
+
+        let mut corners = [v3(0., 0., 0.); 8];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            let ndc = v3(
+                if i & 1 == 0 { -1. } else { 1. },
+                if i & 2 == 0 { -1. } else { 1. },
+                if i & 4 == 0 { 1. } else { -1. },
+            );
+            *corner = matrix_transform(ndc, inverse);
+        }
+
+        corners
+    }
+}
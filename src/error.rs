@@ -0,0 +1,98 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        path: PathBuf,
+        source: ::std::io::Error,
+    },
+    Image {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    Parse {
+        path: PathBuf,
+        line: usize,
+        token: String,
+        message: &'static str,
+    },
+    Scene {
+        path: PathBuf,
+        message: String,
+    },
+}
+
+impl Error {
+    pub fn io<P: AsRef<Path>>(path: P, source: ::std::io::Error) -> Error {
+        Error::Io {
+            path: path.as_ref().to_path_buf(),
+            source,
+        }
+    }
+
+    pub fn image<P: AsRef<Path>>(path: P, source: image::ImageError) -> Error {
+        Error::Image {
+            path: path.as_ref().to_path_buf(),
+            source,
+        }
+    }
+
+    pub fn parse<P: AsRef<Path>>(
+        path: P,
+        line: usize,
+        token: &str,
+        message: &'static str,
+    ) -> Error {
+        Error::Parse {
+            path: path.as_ref().to_path_buf(),
+            line,
+            token: token.to_string(),
+            message,
+        }
+    }
+
+    pub fn scene<P: AsRef<Path>, S: Into<String>>(path: P, message: S) -> Error {
+        Error::Scene {
+            path: path.as_ref().to_path_buf(),
+            message: message.into(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            Error::Io { path, .. }
+            | Error::Image { path, .. }
+            | Error::Parse { path, .. }
+            | Error::Scene { path, .. } => path,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::Image { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::Parse {
+                path,
+                line,
+                token,
+                message,
+            } => write!(f, "{}:{}: {} `{}`", path.display(), line, message, token),
+            Error::Scene { path, message } => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl ::std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn ::std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Image { source, .. } => Some(source),
+            Error::Parse { .. } | Error::Scene { .. } => None,
+        }
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
@@ -0,0 +1,74 @@
+use crate::model::Model;
+use crate::{SquareMatrix, M4};
+
+use std::sync::Arc;
+
+// A node places its model and all of its children relative to its parent.
+// The payload is whatever else its owner wants to keep with the node, the
+// scene keeps how its model is drawn there.
+#[derive(Clone)]
+pub struct Node<T = ()> {
+    pub name: Option<String>,
+    pub transform: M4,
+    pub model: Option<Arc<Model>>,
+    pub payload: T,
+    pub children: Vec<Node<T>>,
+}
+
+impl<T: Default> Node<T> {
+    pub fn new(transform: M4) -> Node<T> {
+        Node {
+            name: None,
+            transform,
+            model: None,
+            payload: T::default(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn with_model(transform: M4, model: Arc<Model>) -> Node<T> {
+        Node {
+            model: Some(model),
+            ..Node::new(transform)
+        }
+    }
+}
+
+impl<T> Node<T> {
+    pub fn add(&mut self, child: Node<T>) -> &mut Node<T> {
+        self.children.push(child);
+        self.children.last_mut().unwrap()
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Node<T>> {
+        if self.name.as_deref() == Some(name) {
+            return Some(self);
+        }
+        self.children.iter().find_map(|child| child.find(name))
+    }
+
+    pub fn find_mut(&mut self, name: &str) -> Option<&mut Node<T>> {
+        if self.name.as_deref() == Some(name) {
+            return Some(self);
+        }
+        self.children
+            .iter_mut()
+            .find_map(|child| child.find_mut(name))
+    }
+
+    // Visits this node and every descendant, parents first, with the world
+    // matrix each one ends up at under `parent`.
+    pub fn traverse<F: FnMut(&Node<T>, M4)>(&self, parent: M4, visit: &mut F) {
+        let world = parent * self.transform;
+        visit(self, world);
+        for child in &self.children {
+            child.traverse(world, visit);
+        }
+    }
+}
+
+impl<T: Default> Default for Node<T> {
+    fn default() -> Node<T> {
+        Node::new(M4::identity())
+    }
+}
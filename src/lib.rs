@@ -0,0 +1,49 @@
+pub extern crate cgmath;
+extern crate image;
+#[cfg(feature = "window")]
+#[macro_use]
+extern crate glium;
+
+pub use cgmath::{vec2 as v2, vec3 as v3, vec4 as v4};
+use cgmath::{
+    ElementWise, InnerSpace, Matrix, Matrix3, Matrix4, SquareMatrix, Vector2, Vector3, Vector4,
+    Zero,
+};
+
+pub type V2 = Vector2<f64>;
+pub type V3 = Vector3<f64>;
+pub type V4 = Vector4<f64>;
+pub type M3 = Matrix3<f64>;
+pub type M4 = Matrix4<f64>;
+
+pub mod camera;
+pub mod error;
+pub mod graph;
+pub mod light;
+pub mod material;
+pub mod model;
+pub mod renderer;
+pub mod scene;
+pub mod shaders;
+pub mod shadow;
+pub mod ssao;
+
+#[cfg(feature = "window")]
+pub mod window;
+
+pub use camera::{Camera, Projection};
+pub use error::{Error, Result};
+pub use graph::Node;
+pub use light::{Attenuation, Light};
+pub use material::Material;
+pub use model::{Face, Model, Vertex};
+pub use renderer::{
+    AddressMode, BilinearSampler, BlendFactor, BlendOp, BlendState, Color, CullMode, Fragment,
+    RenderContext, Renderer, SamplerConfig, Shader, Surface, Texture, TrilinearSampler, Varying,
+    Winding,
+};
+pub use scene::Scene;
+pub use shadow::{
+    CascadeParams, CascadedShadowMap, CubeShadowMap, ShadowFilter, ShadowMap, ShadowParams,
+};
+pub use ssao::SsaoParams;
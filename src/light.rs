@@ -0,0 +1,165 @@
+use crate::{InnerSpace, M4, V3};
+
+// How a point or spot light falls off with distance d,
+// 1 / (constant + linear * d + quadratic * d^2)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Attenuation {
+    pub constant: f64,
+    pub linear: f64,
+    pub quadratic: f64,
+}
+
+impl Attenuation {
+    pub const NONE: Attenuation = Attenuation {
+        constant: 1.,
+        linear: 0.,
+        quadratic: 0.,
+    };
+
+    pub fn factor(&self, distance: f64) -> f64 {
+        1. / (self.constant + self.linear * distance + self.quadratic * distance * distance)
+    }
+}
+
+impl Default for Attenuation {
+    fn default() -> Attenuation {
+        Attenuation::NONE
+    }
+}
+
+// Lights are given in world space. A directional light's direction points
+// towards the light, a spot light's direction is the way it shines. Cone
+// angles are in radians from the spot's axis, full intensity inside inner
+// fading to nothing at outer.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Light {
+    Directional {
+        direction: V3,
+        color: V3,
+        intensity: f64,
+    },
+    Point {
+        position: V3,
+        color: V3,
+        intensity: f64,
+        attenuation: Attenuation,
+    },
+    Spot {
+        position: V3,
+        direction: V3,
+        color: V3,
+        intensity: f64,
+        attenuation: Attenuation,
+        inner: f64,
+        outer: f64,
+    },
+}
+
+impl Light {
+    pub fn color(&self) -> V3 {
+        match *self {
+            Light::Directional { color, .. }
+            | Light::Point { color, .. }
+            | Light::Spot { color, .. } => color,
+        }
+    }
+
+    pub fn intensity(&self) -> f64 {
+        match *self {
+            Light::Directional { intensity, .. }
+            | Light::Point { intensity, .. }
+            | Light::Spot { intensity, .. } => intensity,
+        }
+    }
+
+    // The same light with its position and direction carried by m
+    pub fn transform(&self, m: M4) -> Light {
+        let point = |p: V3| (m * p.extend(1.)).truncate();
+        let vector = |v: V3| (m * v.extend(0.)).truncate().normalize();
+
+        match *self {
+            Light::Directional {
+                direction,
+                color,
+                intensity,
+            } => Light::Directional {
+                direction: vector(direction),
+                color,
+                intensity,
+            },
+            Light::Point {
+                position,
+                color,
+                intensity,
+                attenuation,
+            } => Light::Point {
+                position: point(position),
+                color,
+                intensity,
+                attenuation,
+            },
+            Light::Spot {
+                position,
+                direction,
+                color,
+                intensity,
+                attenuation,
+                inner,
+                outer,
+            } => Light::Spot {
+                position: point(position),
+                direction: vector(direction),
+                color,
+                intensity,
+                attenuation,
+                inner,
+                outer,
+            },
+        }
+    }
+
+    // The unit vector from p towards the light and the light's color scaled
+    // by how much of it reaches p, both in the space the light is in.
+    pub fn illuminate(&self, p: V3) -> (V3, V3) {
+        match *self {
+            Light::Directional {
+                direction,
+                color,
+                intensity,
+            } => (direction.normalize(), color * intensity),
+            Light::Point {
+                position,
+                color,
+                intensity,
+                attenuation,
+            } => {
+                let to_light = position - p;
+                let distance = to_light.magnitude();
+                (
+                    to_light / distance,
+                    color * intensity * attenuation.factor(distance),
+                )
+            }
+            Light::Spot {
+                position,
+                direction,
+                color,
+                intensity,
+                attenuation,
+                inner,
+                outer,
+            } => {
+                let to_light = position - p;
+                let distance = to_light.magnitude();
+                let l = to_light / distance;
+
+                let (cos_inner, cos_outer) = (inner.cos(), outer.cos());
+                let cos = -l.dot(direction.normalize());
+                let t = ((cos - cos_outer) / (cos_inner - cos_outer).max(1e-6)).clamp(0., 1.);
+                let cone = t * t * (3. - 2. * t);
+
+                (l, color * intensity * attenuation.factor(distance) * cone)
+            }
+        }
+    }
+}
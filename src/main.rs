@@ -1,156 +1,140 @@
-extern crate cgmath;
-extern crate image;
-#[macro_use]
-extern crate glium;
+use mass_renderer::{CullMode, Error, Renderer, Scene};
 
-use cgmath::{
-    vec2 as v2, vec3 as v3, vec4 as v4, ElementWise, InnerSpace, Matrix, Matrix3, Matrix4,
-    SquareMatrix, Vector2, Vector3, Vector4,
-};
+#[cfg(feature = "window")]
+use mass_renderer::window::Window;
 
-type V2 = Vector2<f64>;
-type V3 = Vector3<f64>;
-type V4 = Vector4<f64>;
-type M3 = Matrix3<f64>;
-type M4 = Matrix4<f64>;
+use std::path::PathBuf;
 
-pub mod renderer;
-use renderer::Renderer;
+const USAGE: &str = "usage: mass-renderer [--scene FILE] [--headless] [--frames N] [--output DIR]
+                     [--samples N]
 
-pub mod shaders;
-use shaders::{DefaultShader, DepthShader, SolidShader};
+    --scene FILE    scene to render, defaults to scenes/head.toml
+    --headless      render to png files instead of a window
+    --frames N      stop after N frames, headless defaults to 1
+    --output DIR    directory headless frames are written to, defaults to .
+    --samples N     samples per pixel, 1, 2, 4, 8 or 16, defaults to 4";
 
-pub mod model;
-use model::Model;
-
-pub mod window;
-use window::Window;
-
-fn main() {
-    let models = head();
-    let floor = floor();
+struct Options {
+    scene: PathBuf,
+    headless: bool,
+    frames: Option<usize>,
+    output: PathBuf,
+    samples: u32,
+}
 
-    let (width, height) = (1024, 1024);
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+        // Without the window feature there is nothing to present to
+        let mut options = Options {
+            scene: PathBuf::from("scenes/head.toml"),
+            headless: !cfg!(feature = "window"),
+            frames: None,
+            output: PathBuf::from("."),
+            samples: 4,
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--scene" => {
+                    options.scene = args.next().ok_or("--scene needs a value")?.into();
+                }
+                "--headless" => options.headless = true,
+                "--frames" => {
+                    let frames = args.next().ok_or("--frames needs a value")?;
+                    let frames = frames
+                        .parse()
+                        .map_err(|_| format!("invalid frame count `{}`", frames))?;
+                    options.frames = Some(frames);
+                }
+                "--output" => {
+                    options.output = args.next().ok_or("--output needs a value")?.into();
+                }
+                "--samples" => {
+                    let samples = args.next().ok_or("--samples needs a value")?;
+                    options.samples = match samples.parse() {
+                        Ok(n @ (1 | 2 | 4 | 8 | 16)) => n,
+                        _ => return Err(format!("invalid sample count `{}`", samples)),
+                    };
+                }
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    ::std::process::exit(0);
+                }
+                _ => return Err(format!("unknown argument `{}`", arg)),
+            }
+        }
 
-    let mut window = Window::new(width, height);
+        if options.headless && options.frames.is_none() {
+            options.frames = Some(1);
+        }
 
-    let mut light_mod = 0.0_f64;
-    let mut renderer = Renderer::new(width, height);
-    while !window.is_closed() {
-        let start = ::std::time::Instant::now();
+        Ok(options)
+    }
+}
 
-        let light_dir = v3(light_mod.cos(), (light_mod.sin() + 2.) / 3., 1.0);
-        let eye = v3(1., 1., 3.);
-        let center = v3(0., 0., 0.);
-        let up = v3(0., 1., 0.);
-
-        let mut shader = DepthShader::new();
-        renderer.viewport(
-            width as f64 / 4.,
-            height as f64 / 4.,
-            width as f64 * 0.5,
-            height as f64 * 0.5,
-        );
-        renderer.projection(0.);
-        renderer.lookat(light_dir, center, up);
-
-        renderer.clear(v3(0., 0., 0.));
-        for model in models.iter().chain(floor.iter()) {
-            renderer.render(&mut shader, &model);
+fn main() {
+    let options = match Options::parse(::std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            ::std::process::exit(2);
         }
+    };
+
+    if let Err(e) = run(&options) {
+        eprintln!("{}", e);
+        ::std::process::exit(1);
+    }
+}
 
-        let depth = renderer.z_buffer().clone();
-        let depth_matrix = renderer.viewport * renderer.projection * renderer.modelview;
+fn run(options: &Options) -> Result<(), Error> {
+    let mut scene = Scene::load(&options.scene)?;
+    let (width, height) = (scene.width, scene.height);
 
-        let mut shader = DefaultShader::new(light_dir, depth, depth_matrix);
+    #[cfg(feature = "window")]
+    let mut window = if options.headless {
+        None
+    } else {
+        Some(Window::new(width, height))
+    };
 
-        renderer.viewport(
-            width as f64 / 8.,
-            height as f64 / 8.,
-            width as f64 * 0.75,
-            height as f64 * 0.75,
-        );
-        renderer.projection(-1.0 / (eye - center).magnitude());
-        renderer.lookat(eye, center, up);
+    if options.headless {
+        ::std::fs::create_dir_all(&options.output).map_err(|e| Error::io(&options.output, e))?;
+    }
 
-        renderer.clear(v3(0.8, 0.8, 1.));
-        for model in models.iter().chain(floor.iter()) {
-            renderer.render(&mut shader, &model);
+    let mut renderer = Renderer::new(width, height, options.samples);
+    renderer.cull(CullMode::Back);
+    let mut frame = 0;
+    while frame < options.frames.unwrap_or(usize::MAX) {
+        #[cfg(feature = "window")]
+        if matches!(&window, Some(w) if w.is_closed()) {
+            break;
         }
 
+        let start = ::std::time::Instant::now();
+
+        scene.render(&mut renderer);
+
         let duration = start.elapsed();
         println!("{}.{:09}s", duration.as_secs(), duration.subsec_nanos());
 
-        window.render(renderer.display_buffer());
+        if options.headless {
+            let path = options.output.join(format!("frame_{:04}.png", frame));
+            renderer.display_buffer().write(path)?;
+        }
 
-        light_mod += 0.1;
-    }
-    renderer.dump();
-}
+        #[cfg(feature = "window")]
+        if let Some(window) = window.as_mut() {
+            window.render(renderer.display_buffer());
+        }
 
-#[allow(dead_code)]
-fn floor() -> Vec<Model> {
-    vec![Model::load(
-        "tinyrenderer/obj/floor.obj",
-        "tinyrenderer/obj/floor_diffuse.tga",
-        "tinyrenderer/obj/floor_diffuse.tga",
-        "tinyrenderer/obj/floor_nm_tangent.tga",
-    )]
-}
+        scene.advance();
+        frame += 1;
+    }
 
-#[allow(dead_code)]
-fn head() -> Vec<Model> {
-    vec![
-        Model::load(
-            "tinyrenderer/obj/african_head/african_head_eye_inner.obj",
-            "tinyrenderer/obj/african_head/african_head_eye_inner_diffuse.tga",
-            "tinyrenderer/obj/african_head/african_head_eye_inner_spec.tga",
-            "tinyrenderer/obj/african_head/african_head_eye_inner_nm_tangent.tga",
-        ),
-        Model::load(
-            "tinyrenderer/obj/african_head/african_head.obj",
-            "tinyrenderer/obj/african_head/african_head_diffuse.tga",
-            "tinyrenderer/obj/african_head/african_head_spec.tga",
-            "tinyrenderer/obj/african_head/african_head_nm_tangent.tga",
-        ),
-        /*
-                Model::load("tinyrenderer/obj/african_head/african_head_eye_outer.obj",
-                           "tinyrenderer/obj/african_head/african_head_eye_outer_diffuse.tga",
-                           "tinyrenderer/obj/african_head/african_head_eye_outer_spec.tga",
-                           "tinyrenderer/obj/african_head/african_head_eye_outer_nm_tangent.tga"),
-        */
-    ]
-}
-#[allow(dead_code)]
-fn diablo() -> Vec<Model> {
-    vec![Model::load(
-        "tinyrenderer/obj/diablo3_pose/diablo3_pose.obj",
-        "tinyrenderer/obj/diablo3_pose/diablo3_pose_diffuse.tga",
-        "tinyrenderer/obj/diablo3_pose/diablo3_pose_spec.tga",
-        "tinyrenderer/obj/diablo3_pose/diablo3_pose_nm_tangent.tga",
-    )]
-}
+    if !options.headless {
+        renderer.dump()?;
+    }
 
-#[allow(dead_code)]
-fn boggie() -> Vec<Model> {
-    vec![
-        Model::load(
-            "tinyrenderer/obj/boggie/body.obj",
-            "tinyrenderer/obj/boggie/body_diffuse.tga",
-            "tinyrenderer/obj/boggie/body_spec.tga",
-            "tinyrenderer/obj/boggie/body_nm_tangent.tga",
-        ),
-        Model::load(
-            "tinyrenderer/obj/boggie/eyes.obj",
-            "tinyrenderer/obj/boggie/eyes_diffuse.tga",
-            "tinyrenderer/obj/boggie/eyes_spec.tga",
-            "tinyrenderer/obj/boggie/eyes_nm_tangent.tga",
-        ),
-        Model::load(
-            "tinyrenderer/obj/boggie/head.obj",
-            "tinyrenderer/obj/boggie/head_diffuse.tga",
-            "tinyrenderer/obj/boggie/head_spec.tga",
-            "tinyrenderer/obj/boggie/head_nm_tangent.tga",
-        ),
-    ]
+    Ok(())
 }
@@ -0,0 +1,178 @@
+use crate::error::{Error, Result};
+use crate::model::ObjLine;
+use crate::renderer::{AddressMode, SamplerConfig, Texture, TrilinearSampler};
+use crate::{v3, v4, ElementWise, V2, V3, V4};
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub type TextureMap = Arc<TrilinearSampler<Texture<V4>>>;
+
+#[derive(Clone)]
+pub struct Material {
+    pub name: String,
+    pub ambient: V3,
+    pub diffuse: V3,
+    pub specular: V3,
+    pub shininess: f64,
+    pub dissolve: f64,
+    pub diffuse_map: Option<TextureMap>,
+    pub specular_map: Option<TextureMap>,
+    pub shininess_map: Option<TextureMap>,
+    pub bump_map: Option<TextureMap>,
+    pub dissolve_map: Option<TextureMap>,
+}
+
+impl Material {
+    pub fn new<S: Into<String>>(name: S) -> Material {
+        Material {
+            name: name.into(),
+            ambient: v3(0., 0., 0.),
+            diffuse: v3(1., 1., 1.),
+            specular: v3(0., 0., 0.),
+            shininess: 1.,
+            dissolve: 1.,
+            diffuse_map: None,
+            specular_map: None,
+            shininess_map: None,
+            bump_map: None,
+            dissolve_map: None,
+        }
+    }
+
+    pub fn load_library<P: AsRef<Path>>(path: P) -> Result<Vec<Material>> {
+        use ::std::io::{BufRead, BufReader};
+
+        let path = path.as_ref();
+        let file = ::std::fs::File::open(path).map_err(|e| Error::io(path, e))?;
+        let reader = BufReader::new(file);
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        let mut textures = HashMap::new();
+        let mut materials: Vec<Material> = Vec::new();
+
+        for (number, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| Error::io(path, e))?;
+            let line = ObjLine {
+                path,
+                number: number + 1,
+                parts: line.split_whitespace().collect(),
+            };
+
+            let keyword = match line.parts.first() {
+                Some(keyword) => *keyword,
+                None => continue,
+            };
+
+            if keyword == "newmtl" {
+                let name = line.parts[1..].join(" ");
+                materials.push(Material::new(name));
+                continue;
+            }
+
+            if keyword.starts_with('#') {
+                continue;
+            }
+
+            let material = match materials.last_mut() {
+                Some(material) => material,
+                None => return Err(line.error(keyword, "statement before newmtl")),
+            };
+
+            match keyword {
+                "Ka" => material.ambient = line.v3()?,
+                "Kd" => material.diffuse = line.v3()?,
+                "Ks" => material.specular = line.v3()?,
+                "Ns" => material.shininess = line.float(1)?,
+                "d" => material.dissolve = line.float(1)?,
+                "Tr" => material.dissolve = 1. - line.float(1)?,
+                "map_Kd" => material.diffuse_map = Some(line.texture(dir, &mut textures)?),
+                "map_Ks" => material.specular_map = Some(line.texture(dir, &mut textures)?),
+                "map_Ns" => material.shininess_map = Some(line.texture(dir, &mut textures)?),
+                "map_bump" | "bump" | "norm" => {
+                    material.bump_map = Some(line.texture(dir, &mut textures)?)
+                }
+                "map_d" => material.dissolve_map = Some(line.texture(dir, &mut textures)?),
+                _ => (),
+            }
+        }
+
+        Ok(materials)
+    }
+
+    pub fn load_map<P: AsRef<Path>>(path: P) -> Result<TextureMap> {
+        // Uvs outside of 0..1 tile the texture
+        let config = SamplerConfig::new(AddressMode::Repeat, v4(0., 0., 0., 0.));
+        Ok(Arc::new(TrilinearSampler::with_config(
+            Texture::from_file(path)?,
+            config,
+        )))
+    }
+
+    // Maps are sampled at uv, ddx and ddy are the uv derivatives across the
+    // screen that pick the mip level.
+    pub fn diffuse(&self, uv: V2, ddx: V2, ddy: V2) -> V4 {
+        let white = v4(1., 1., 1., 1.);
+        let color = sample(&self.diffuse_map, uv, ddx, ddy, white);
+        let alpha = color.w * self.dissolve * sample(&self.dissolve_map, uv, ddx, ddy, white).x;
+
+        color
+            .truncate()
+            .mul_element_wise(self.diffuse)
+            .extend(alpha)
+    }
+
+    pub fn specular(&self, uv: V2, ddx: V2, ddy: V2) -> V3 {
+        sample(&self.specular_map, uv, ddx, ddy, v4(1., 1., 1., 1.))
+            .truncate()
+            .mul_element_wise(self.specular)
+    }
+
+    pub fn shininess(&self, uv: V2, ddx: V2, ddy: V2) -> f64 {
+        self.shininess * sample(&self.shininess_map, uv, ddx, ddy, v4(1., 1., 1., 1.)).x
+    }
+
+    pub fn normal(&self, uv: V2, ddx: V2, ddy: V2) -> V3 {
+        (sample(&self.bump_map, uv, ddx, ddy, v4(0.5, 0.5, 1., 1.)) * 2.)
+            .sub_element_wise(1.)
+            .truncate()
+    }
+}
+
+impl Default for Material {
+    fn default() -> Material {
+        Material::new("default")
+    }
+}
+
+fn sample(map: &Option<TextureMap>, uv: V2, ddx: V2, ddy: V2, default: V4) -> V4 {
+    match map {
+        Some(map) => map.get_grad(uv.x, uv.y, ddx, ddy),
+        None => default,
+    }
+}
+
+impl<'a> ObjLine<'a> {
+    // Texture options come before the file name, so only the last token is used
+    fn texture(
+        &self,
+        dir: &Path,
+        textures: &mut HashMap<PathBuf, TextureMap>,
+    ) -> Result<TextureMap> {
+        let file = match self.parts.get(1..) {
+            Some(args) if !args.is_empty() => args[args.len() - 1],
+            _ => return Err(self.error(self.parts[0], "missing texture file")),
+        };
+
+        let path = dir.join(file);
+        if let Some(texture) = textures.get(&path) {
+            return Ok(texture.clone());
+        }
+
+        let texture = Material::load_map(&path)?;
+        textures.insert(path, texture.clone());
+
+        Ok(texture)
+    }
+}
@@ -1,163 +1,201 @@
-use crate::renderer::{Surface, Texture};
-use crate::{v2, v3, ElementWise, V2, V3, V4};
+use crate::error::{Error, Result};
+use crate::material::Material;
+use crate::{v2, v3, InnerSpace, V2, V3};
 
-pub struct Face {
-    pub verts: Vec<V3>,
-    pub texs: Vec<V2>,
-    pub norms: Vec<V3>,
+use std::collections::HashMap;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Vertex {
+    pub position: V3,
+    pub uv: V2,
+    pub normal: V3,
+}
+
+#[derive(Copy, Clone)]
+pub struct Face<'a> {
+    pub indices: [usize; 3],
+    pub verts: [&'a Vertex; 3],
+    pub has_texs: bool,
+    pub has_norms: bool,
+    pub material: usize,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+struct FaceVertex {
+    vert: usize,
+    tex: Option<usize>,
+    norm: Option<usize>,
+}
+
+struct ObjFace {
+    verts: [FaceVertex; 3],
+    material: usize,
+}
+
+struct Triangle {
+    indices: [usize; 3],
+    has_texs: bool,
+    has_norms: bool,
+    material: usize,
 }
 
 pub struct Model {
-    faces: Vec<Vec<Vec<usize>>>,
-    verts: Vec<V3>,
-    uvs: Vec<V2>,
-    norms: Vec<V3>,
-    diffuse: Texture<V4>,
-    specular: Texture<V4>,
-    normal: Texture<V4>,
+    vertices: Vec<Vertex>,
+    triangles: Vec<Triangle>,
+    materials: Vec<Material>,
 }
 
 use ::std::path::Path;
 impl Model {
-    pub fn load<G, D, S, N>(geometry: G, diffuse: D, specular: S, normal: N) -> Model
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Model> {
+        Self::load_obj(path.as_ref(), true)
+    }
+
+    pub fn with_textures<G, D, S, N>(
+        geometry: G,
+        diffuse: D,
+        specular: S,
+        normal: N,
+    ) -> Result<Model>
     where
         G: AsRef<Path>,
         D: AsRef<Path>,
         S: AsRef<Path>,
         N: AsRef<Path>,
     {
-        use ::std::io::{BufRead, BufReader};
-        enum ModelObj {
-            Vert(V3),
-            Tex(V2),
-            Norm(V3),
-            Face(Vec<Vec<usize>>),
-            Invalid,
+        let mut model = Self::load_obj(geometry.as_ref(), false)?;
+        let material = Material {
+            ambient: v3(0.02, 0.02, 0.02),
+            specular: v3(0.6, 0.6, 0.6),
+            shininess: 255.,
+            diffuse_map: Some(Material::load_map(diffuse)?),
+            shininess_map: Some(Material::load_map(specular)?),
+            bump_map: Some(Material::load_map(normal)?),
+            ..Material::default()
+        };
+
+        model.materials = vec![material];
+        for triangle in model.triangles.iter_mut() {
+            triangle.material = 0;
         }
 
-        let reader = BufReader::new(::std::fs::File::open(geometry).unwrap());
-        let (verts, uvs, norms, faces, valid) = reader
-            .lines()
-            .filter_map(|l| {
-                let l = l.unwrap();
-                let parts: Vec<&str> = l.split(' ').filter(|x| x.len() > 0).collect();
-                if parts.len() == 0 {
-                    return None;
-                }
+        Ok(model)
+    }
 
-                match parts[0] {
-                    "v" => {
-                        if parts.len() < 4 {
-                            Some(ModelObj::Invalid)
-                        } else {
-                            let x = parts[1].parse();
-                            let y = parts[2].parse();
-                            let z = parts[3].parse();
-
-                            if x.is_err() || y.is_err() || z.is_err() {
-                                None
-                            } else {
-                                Some(ModelObj::Vert(v3(x.unwrap(), y.unwrap(), z.unwrap())))
-                            }
-                        }
-                    }
-                    "vt" => {
-                        if parts.len() < 3 {
-                            Some(ModelObj::Invalid)
-                        } else {
-                            let x = parts[1].parse();
-                            let y = parts[2].parse();
-
-                            if x.is_err() || y.is_err() {
-                                None
-                            } else {
-                                Some(ModelObj::Tex(v2(x.unwrap(), y.unwrap())))
-                            }
-                        }
+    fn load_obj(path: &Path, with_materials: bool) -> Result<Model> {
+        use ::std::io::{BufRead, BufReader};
+
+        let file = ::std::fs::File::open(path).map_err(|e| Error::io(path, e))?;
+        let reader = BufReader::new(file);
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        let mut verts = Vec::new();
+        let mut uvs = Vec::new();
+        let mut norms = Vec::new();
+        let mut faces = Vec::new();
+        let mut materials = vec![Material::default()];
+        let mut material = 0;
+
+        for (number, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| Error::io(path, e))?;
+            let line = ObjLine {
+                path,
+                number: number + 1,
+                parts: line.split_whitespace().collect(),
+            };
+
+            match line.parts.first() {
+                Some(&"v") => verts.push(line.v3()?),
+                Some(&"vt") => uvs.push(line.uv()?),
+                Some(&"vn") => norms.push(line.v3()?),
+                Some(&"f") => {
+                    if line.parts.len() < 4 {
+                        return Err(line.error(line.parts[0], "face needs at least 3 vertices"));
                     }
-                    "vn" => {
-                        if parts.len() < 4 {
-                            Some(ModelObj::Invalid)
-                        } else {
-                            let x = parts[1].parse();
-                            let y = parts[2].parse();
-                            let z = parts[3].parse();
-
-                            if x.is_err() || y.is_err() || z.is_err() {
-                                None
-                            } else {
-                                Some(ModelObj::Norm(v3(x.unwrap(), y.unwrap(), z.unwrap())))
-                            }
-                        }
+
+                    let polygon = line.parts[1..]
+                        .iter()
+                        .map(|p| line.face_vertex(p, verts.len(), uvs.len(), norms.len()))
+                        .collect::<Result<Vec<_>>>()?;
+
+                    for i in 2..polygon.len() {
+                        faces.push(ObjFace {
+                            verts: [polygon[0], polygon[i - 1], polygon[i]],
+                            material,
+                        });
                     }
-                    "f" => {
-                        if parts.len() < 4 {
-                            Some(ModelObj::Invalid)
-                        } else {
-                            let mut invalid = false;
-                            let faces = parts[1..]
-                                .iter()
-                                .map(|p| {
-                                    let v: Vec<usize> = p
-                                        .split('/')
-                                        .map(|x| {
-                                            let x = x.parse();
-                                            if x.is_err() {
-                                                invalid = true;
-                                                0
-                                            } else {
-                                                x.unwrap()
-                                            }
-                                        })
-                                        .collect();
-
-                                    if v.len() != 3 {
-                                        invalid = true;
-                                    }
-
-                                    v
-                                })
-                                .take(3)
-                                .collect();
-
-                            if invalid {
-                                Some(ModelObj::Invalid)
-                            } else {
-                                Some(ModelObj::Face(faces))
-                            }
-                        }
+                }
+                Some(&"mtllib") if with_materials => {
+                    for library in &line.parts[1..] {
+                        materials.extend(Material::load_library(dir.join(library))?);
                     }
-                    _ => None,
                 }
-            })
-            .fold(
-                (Vec::new(), Vec::new(), Vec::new(), Vec::new(), true),
-                |mut col, item| {
-                    match item {
-                        ModelObj::Vert(v) => col.0.push(v),
-                        ModelObj::Face(f) => col.3.push(f),
-                        ModelObj::Tex(t) => col.1.push(t),
-                        ModelObj::Norm(n) => col.2.push(n),
-                        ModelObj::Invalid => col.4 = false,
+                Some(&"usemtl") if with_materials => {
+                    let name = line.parts[1..].join(" ");
+                    material = match materials.iter().rposition(|m| m.name == name) {
+                        Some(index) => index,
+                        None => return Err(line.error(&name, "unknown material")),
                     };
+                }
+                _ => (),
+            }
+        }
 
-                    col
-                },
-            );
+        let mut vertices = Vec::new();
+        let mut triangles = Vec::with_capacity(faces.len());
+        let mut indices = HashMap::new();
 
-        if !valid {
-            panic!("Invalid .obj file");
-        }
+        for face in faces {
+            let positions = [
+                verts[face.verts[0].vert],
+                verts[face.verts[1].vert],
+                verts[face.verts[2].vert],
+            ];
+            let has_texs = face.verts.iter().all(|f| f.tex.is_some());
+            let has_norms = face.verts.iter().all(|f| f.norm.is_some());
 
-        Model {
-            faces,
-            verts,
-            uvs,
-            norms,
-            diffuse: Texture::from_file(diffuse),
-            specular: Texture::from_file(specular),
-            normal: Texture::from_file(normal),
+            // Faces missing uvs get the texture origin, faces missing normals are
+            // flat shaded with the geometric normal of the triangle so their
+            // vertices can't be shared with neighbouring faces.
+            let flat = (positions[1] - positions[0])
+                .cross(positions[2] - positions[0])
+                .normalize();
+
+            let mut triangle = [0; 3];
+            for (i, f) in face.verts.iter().enumerate() {
+                let vertex = Vertex {
+                    position: positions[i],
+                    uv: f.tex.map_or(v2(0., 0.), |t| uvs[t]),
+                    normal: match f.norm {
+                        Some(n) if has_norms => norms[n],
+                        _ => flat,
+                    },
+                };
+
+                triangle[i] = if has_norms {
+                    *indices.entry(*f).or_insert_with(|| {
+                        vertices.push(vertex);
+                        vertices.len() - 1
+                    })
+                } else {
+                    vertices.push(vertex);
+                    vertices.len() - 1
+                };
+            }
+
+            triangles.push(Triangle {
+                indices: triangle,
+                has_texs,
+                has_norms,
+                material: face.material,
+            });
         }
+
+        Ok(Model {
+            vertices,
+            triangles,
+            materials,
+        })
     }
 
     pub fn faces<'a>(&'a self) -> FaceIterator<'a> {
@@ -167,18 +205,101 @@ impl Model {
         }
     }
 
-    pub fn diffuse(&self, uv: V2) -> V4 {
-        self.diffuse.get_f(uv.x, uv.y)
+    pub fn face(&self, index: usize) -> Face<'_> {
+        let triangle = &self.triangles[index];
+        let [a, b, c] = triangle.indices;
+
+        Face {
+            indices: triangle.indices,
+            verts: [&self.vertices[a], &self.vertices[b], &self.vertices[c]],
+            has_texs: triangle.has_texs,
+            has_norms: triangle.has_norms,
+            material: triangle.material,
+        }
+    }
+
+    pub fn face_count(&self) -> usize {
+        self.triangles.len()
     }
 
-    pub fn specular(&self, uv: V2) -> f64 {
-        self.specular.get_f(uv.x, uv.y).x * 255.
+    pub fn vertices(&self) -> &[Vertex] {
+        &self.vertices
     }
 
-    pub fn normal(&self, uv: V2) -> V3 {
-        (self.normal.get_f(uv.x, uv.y) * 2.)
-            .sub_element_wise(1.)
-            .truncate()
+    pub fn materials(&self) -> &[Material] {
+        &self.materials
+    }
+
+    pub fn material(&self, index: usize) -> &Material {
+        &self.materials[index]
+    }
+}
+
+pub(crate) struct ObjLine<'a> {
+    pub path: &'a Path,
+    pub number: usize,
+    pub parts: Vec<&'a str>,
+}
+
+impl<'a> ObjLine<'a> {
+    pub fn error(&self, token: &str, message: &'static str) -> Error {
+        Error::parse(self.path, self.number, token, message)
+    }
+
+    pub fn float(&self, index: usize) -> Result<f64> {
+        match self.parts.get(index) {
+            Some(token) => token
+                .parse()
+                .map_err(|_| self.error(token, "expected a number")),
+            None => Err(self.error(self.parts[0], "missing coordinate")),
+        }
+    }
+
+    // Texture coordinates are `vt u [v [w]]`, v defaults to 0
+    pub fn uv(&self) -> Result<V2> {
+        let v = match self.parts.get(2) {
+            Some(_) => self.float(2)?,
+            None => 0.,
+        };
+        Ok(v2(self.float(1)?, v))
+    }
+
+    pub fn v3(&self) -> Result<V3> {
+        Ok(v3(self.float(1)?, self.float(2)?, self.float(3)?))
+    }
+
+    fn face_vertex(
+        &self,
+        token: &str,
+        verts: usize,
+        uvs: usize,
+        norms: usize,
+    ) -> Result<FaceVertex> {
+        let indices: Vec<&str> = token.split('/').collect();
+        let optional = |i: usize, len: usize| match indices.get(i) {
+            Some(index) if !index.is_empty() => self.index(index, len).map(Some),
+            _ => Ok(None),
+        };
+
+        if indices.len() > 3 {
+            return Err(self.error(token, "expected v, v/vt, v//vn or v/vt/vn"));
+        }
+
+        Ok(FaceVertex {
+            vert: self.index(indices[0], verts)?,
+            tex: optional(1, uvs)?,
+            norm: optional(2, norms)?,
+        })
+    }
+
+    // Resolves a one based or negative relative index into a zero based one.
+    fn index(&self, token: &str, len: usize) -> Result<usize> {
+        match token.parse::<isize>() {
+            Ok(i) if i >= 1 && i as usize <= len => Ok(i as usize - 1),
+            Ok(i) if i < 0 && i.unsigned_abs() <= len => Ok(len - i.unsigned_abs()),
+            Ok(_) => Err(self.error(token, "index out of range")),
+            Err(_) => Err(self.error(token, "expected an index")),
+        }
     }
 }
 
@@ -188,27 +309,140 @@ pub struct FaceIterator<'a> {
 }
 
 impl<'a> Iterator for FaceIterator<'a> {
-    type Item = Face;
+    type Item = Face<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(next) = self.model.faces.get(self.cur) {
+        if self.cur < self.model.triangles.len() {
             self.cur += 1;
-            let (v, t, n) = next
-                .iter()
-                .fold((Vec::new(), Vec::new(), Vec::new()), |mut a, t| {
-                    a.0.push(*self.model.verts.get(t[0] - 1).unwrap());
-                    a.1.push(*self.model.uvs.get(t[1] - 1).unwrap());
-                    a.2.push(*self.model.norms.get(t[2] - 1).unwrap());
-                    a
-                });
-
-            Some(Face {
-                verts: v,
-                texs: t,
-                norms: n,
-            })
+            Some(self.model.face(self.cur - 1))
         } else {
             None
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    fn write_obj(name: &str, source: &str) -> PathBuf {
+        let path = ::std::env::temp_dir().join(format!(
+            "mass-renderer-{}-{}.obj",
+            ::std::process::id(),
+            name
+        ));
+        ::std::fs::write(&path, source).unwrap();
+        path
+    }
+
+    // Loads a model from obj source, tests elsewhere build their geometry
+    // with it too.
+    pub(crate) fn parse(name: &str, source: &str) -> Result<Model> {
+        let path = write_obj(name, source);
+        let model = Model::load(&path);
+        ::std::fs::remove_file(&path).unwrap();
+        model
+    }
+
+    const QUAD: &str = "v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vt 1 0
+vt 1 1
+vn 0 0 1
+";
+
+    #[test]
+    fn face_index_forms() {
+        let source = format!(
+            "{}f 1 2 3\nf 1/1 2/2 3/3\nf 1//1 2//1 3//1\nf 1/1/1 2/2/1 3/3/1\nf -4 -3 -2\n",
+            QUAD
+        );
+        let model = parse("forms", &source).unwrap();
+        assert_eq!(model.face_count(), 5);
+
+        let flags: Vec<(bool, bool)> = model.faces().map(|f| (f.has_texs, f.has_norms)).collect();
+        assert_eq!(
+            flags,
+            vec![
+                (false, false),
+                (true, false),
+                (false, true),
+                (true, true),
+                (false, false)
+            ]
+        );
+
+        for face in model.faces() {
+            let positions: Vec<V3> = face.verts.iter().map(|v| v.position).collect();
+            assert_eq!(
+                positions,
+                vec![v3(0., 0., 0.), v3(1., 0., 0.), v3(1., 1., 0.)]
+            );
+        }
+
+        let textured = model.face(1);
+        assert_eq!(textured.verts[1].uv, v2(1., 0.));
+        assert_eq!(textured.verts[2].uv, v2(1., 1.));
+
+        let normals = model.face(2);
+        assert!(normals.verts.iter().all(|v| v.normal == v3(0., 0., 1.)));
+    }
+
+    #[test]
+    fn texture_coordinates_default_v() {
+        let source = "v 0 0 0\nv 1 0 0\nv 1 1 0\nvt 0.5\nvt 0.25 0.75 0\nf 1/1 2/2 3/1\n";
+        let model = parse("uv", source).unwrap();
+        let face = model.face(0);
+        assert_eq!(face.verts[0].uv, v2(0.5, 0.));
+        assert_eq!(face.verts[1].uv, v2(0.25, 0.75));
+
+        assert!(parse("uv", "vt\n").is_err());
+    }
+
+    #[test]
+    fn shared_vertices_are_indexed_once() {
+        let source = format!("{}f 1//1 2//1 3//1\nf 1//1 3//1 4//1\n", QUAD);
+        let shared = parse("shared", &source).unwrap();
+        assert_eq!(shared.face_count(), 2);
+        assert_eq!(shared.vertices().len(), 4);
+        assert_eq!(shared.face(1).indices, [0, 2, 3]);
+
+        // Flat shaded faces keep their own vertices
+        let flat = parse("flat", &format!("{}f 1 2 3\nf 1 3 4\n", QUAD)).unwrap();
+        assert_eq!(flat.vertices().len(), 6);
+    }
+
+    #[test]
+    fn polygons_are_fanned() {
+        let model = parse("fan", &format!("{}f 1 2 3 4\n", QUAD)).unwrap();
+        assert_eq!(model.face_count(), 2);
+
+        let second: Vec<V3> = model.face(1).verts.iter().map(|v| v.position).collect();
+        assert_eq!(second, vec![v3(0., 0., 0.), v3(1., 1., 0.), v3(0., 1., 0.)]);
+    }
+
+    #[test]
+    fn out_of_range_indices() {
+        for (face, bad) in &[
+            ("f 1 2 5", "5"),
+            ("f 0 1 2", "0"),
+            ("f -5 1 2", "-5"),
+            ("f 1/4 2/1 3/1", "4"),
+            ("f 1//2 2//1 3//1", "2"),
+        ] {
+            match parse("range", &format!("{}{}\n", QUAD, face)) {
+                Err(Error::Parse { line, token, .. }) => {
+                    assert_eq!(line, 9, "{}", face);
+                    assert_eq!(token, *bad, "{}", face);
+                }
+                Err(e) => panic!("{}: unexpected error {}", face, e),
+                Ok(_) => panic!("{}: loaded", face),
+            }
+        }
+    }
+}
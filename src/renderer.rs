@@ -1,79 +1,346 @@
-use crate::model::{Face, Model};
+use crate::camera::Camera;
+use crate::error::{Error, Result};
+use crate::light::Light;
+use crate::material::Material;
+use crate::model::{Face, Model, Vertex};
 
-use crate::{image, v2, v3, v4, InnerSpace, SquareMatrix, M4, V2, V3, V4};
+use crate::{
+    image, v2, v3, v4, ElementWise, InnerSpace, Matrix, SquareMatrix, Zero, M3, M4, V2, V3, V4,
+};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub struct Renderer {
     display_buf: Texture<V3>,
     z_buf: Texture<f64>,
+    color_samples: Texture<V4>,
+    depth_samples: Texture<f64>,
+    samples: Vec<[i64; 2]>,
     width: u32,
     height: u32,
+    threads: usize,
+    cull_mode: CullMode,
+    winding: Winding,
+    blend: BlendState,
+    depth_write: bool,
+    lights: Vec<Light>,
     pub viewport: M4,
     pub projection: M4,
     pub modelview: M4,
 }
 
 impl Renderer {
-    pub fn new(width: u32, height: u32) -> Renderer {
+    // Every pixel keeps `samples` colors and depths which are resolved into
+    // the display and depth buffers after each draw, 1 disables multisampling.
+    // There are patterns for 1, 2, 4, 8 and 16 samples, other counts take the
+    // largest of them that isn't above it and 0 is taken as 1.
+    pub fn new(width: u32, height: u32, samples: u32) -> Renderer {
+        let threads = ::std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        let pattern = sample_pattern(samples);
+        let samples = pattern.len() as u32;
+
         Renderer {
             display_buf: Texture::new(width, height, v3(0., 0., 0.)),
-            z_buf: Texture::new(width, height, ::std::f64::MIN),
+            z_buf: Texture::new(width, height, f64::MIN),
+            color_samples: Texture::new(width * samples, height, v4(0., 0., 0., 1.)),
+            depth_samples: Texture::new(width * samples, height, f64::MIN),
+            samples: pattern,
             width,
             height,
+            threads,
+            cull_mode: CullMode::None,
+            winding: Winding::CounterClockwise,
+            blend: BlendState::REPLACE,
+            depth_write: true,
+            lights: Vec::new(),
             viewport: M4::identity(),
             projection: M4::identity(),
             modelview: M4::identity(),
         }
     }
 
-    pub fn display_buffer<'a>(&'a self) -> &'a Texture<V3> {
+    pub fn display_buffer(&self) -> &Texture<V3> {
         &self.display_buf
     }
 
-    pub fn z_buffer<'a>(&'a self) -> &'a Texture<f64> {
+    pub fn z_buffer(&self) -> &Texture<f64> {
         &self.z_buf
     }
 
+    pub fn samples(&self) -> u32 {
+        self.samples.len() as u32
+    }
+
     pub fn clear(&mut self, color: V3) {
+        let samples = self.samples();
         self.display_buf = Texture::new(self.width, self.height, color);
-        self.z_buf = Texture::new(self.width, self.height, ::std::f64::MIN);
+        self.z_buf = Texture::new(self.width, self.height, f64::MIN);
+        self.color_samples = Texture::new(self.width * samples, self.height, color.extend(1.));
+        self.depth_samples = Texture::new(self.width * samples, self.height, f64::MIN);
+    }
+
+    pub fn threads(&mut self, count: usize) {
+        self.threads = count.max(1);
+    }
+
+    pub fn cull(&mut self, mode: CullMode) {
+        self.cull_mode = mode;
+    }
+
+    pub fn winding(&mut self, winding: Winding) {
+        self.winding = winding;
+    }
+
+    // How fragments are combined with the color already in the buffer
+    pub fn blend(&mut self, state: BlendState) {
+        self.blend = state;
+    }
+
+    // Depth is still tested when writes are off, translucent geometry drawn
+    // after the opaque geometry is hidden by it without hiding itself.
+    pub fn depth_write(&mut self, enabled: bool) {
+        self.depth_write = enabled;
+    }
+
+    // World space lights handed to shaders through the RenderContext
+    pub fn lights(&mut self, lights: &[Light]) {
+        self.lights = lights.to_vec();
     }
 
     pub fn render<S: Shader>(&mut self, shader: &mut S, model: &Model) {
-        let ctx = RenderContext {
-            viewport: self.viewport,
-            projection: self.projection,
-            modelview: self.modelview,
-            model: &model,
-        };
-        shader.prepare(&ctx);
+        self.render_instanced(shader, model, &[M4::identity()]);
+    }
+
+    // Draws the model once for each model matrix, placing it in the world
+    // before the modelview is applied.
+    pub fn render_instanced<S: Shader>(&mut self, shader: &mut S, model: &Model, instances: &[M4]) {
+        let lights = self.lights.clone();
+        for &model_matrix in instances {
+            let modelview = self.modelview * model_matrix;
+            let ctx = RenderContext {
+                viewport: self.viewport,
+                projection: self.projection,
+                view: self.modelview,
+                model_matrix,
+                modelview,
+                normal_matrix: normal_matrix(modelview),
+                model,
+                lights: &lights,
+            };
+            self.draw(shader, &ctx);
+        }
+    }
+
+    fn draw<S: Shader>(&mut self, shader: &mut S, ctx: &RenderContext) {
+        let model = ctx.model;
+        shader.prepare(ctx);
+        let planes = self.clip_planes();
+        let mut triangles = Vec::new();
+        let mut vertices = vec![None; model.vertices().len()];
         for face in model.faces() {
-            self.triangle(shader, &ctx, &face);
+            self.triangle(shader, ctx, face, &planes, &mut vertices, &mut triangles);
+        }
+
+        let tiles = self.bin(&triangles);
+        let next_tile = AtomicUsize::new(0);
+        let this = &*self;
+        let shade = |mut shader: S| {
+            let mut shaded = Vec::new();
+            loop {
+                let index = next_tile.fetch_add(1, Ordering::Relaxed);
+                match tiles.get(index) {
+                    Some(tile) => shaded.push(this.shade_tile(&mut shader, ctx, &triangles, tile)),
+                    None => break shaded,
+                }
+            }
+        };
+
+        let shaded: Vec<ShadedTile> = if self.threads == 1 {
+            shade(shader.clone())
+        } else {
+            ::std::thread::scope(|scope| {
+                let workers: Vec<_> = (0..self.threads)
+                    .map(|_| {
+                        let shader = shader.clone();
+                        scope.spawn(|| shade(shader))
+                    })
+                    .collect();
+
+                workers
+                    .into_iter()
+                    .flat_map(|w| w.join().unwrap())
+                    .collect()
+            })
+        };
+
+        let samples = self.samples();
+        for tile in shaded {
+            self.color_samples
+                .blit(tile.x * samples, tile.y, &tile.color);
+            self.depth_samples
+                .blit(tile.x * samples, tile.y, &tile.depth);
+            self.resolve(
+                tile.x,
+                tile.y,
+                tile.color.width / samples,
+                tile.color.height,
+            );
+        }
+    }
+
+    // Pixels get the average color of their samples and the closest depth
+    fn resolve(&mut self, x: u32, y: u32, width: u32, height: u32) {
+        let samples = self.samples();
+        for py in y..y + height {
+            for px in x..x + width {
+                let mut color = v3(0., 0., 0.);
+                let mut depth = f64::MIN;
+                for i in px * samples..(px + 1) * samples {
+                    color += self.color_samples.get(i, py).truncate();
+                    depth = depth.max(self.depth_samples.get(i, py));
+                }
+                self.display_buf.set(px, py, color / samples as f64);
+                self.z_buf.set(px, py, depth);
+            }
+        }
+    }
+
+    // Scales every pixel of the display buffer by its factor, an ambient
+    // occlusion term for instance
+    pub fn modulate(&mut self, factors: &Texture<f64>) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let color = self.display_buf.get(x, y) * factors.get(x, y);
+                self.display_buf.set(x, y, color);
+            }
         }
     }
 
-    pub fn dump(&self) {
-        let _ = self.display_buf.write("image.png");
-        let _ = self.z_buf.write("z_buf.png");
+    pub fn dump(&self) -> Result<()> {
+        self.display_buf.write("image.png")?;
+        self.z_buf.write("z_buf.png")
+    }
+
+    fn clip_planes(&self) -> Vec<ClipPlane> {
+        // Shaders output positions with the viewport already applied, so the
+        // clip space planes are carried through the inverse viewport. The near
+        // and far planes bound depth, x and y only need clipping to the guard
+        // band which keeps the fixed point edge functions from overflowing.
+        let inv_viewport = self
+            .viewport
+            .invert()
+            .unwrap_or_else(M4::identity)
+            .transpose();
+        let clip_space = [
+            (v4(0., 0., 0., 1.), W_EPSILON),
+            (v4(0., 0., -1., 1.), 0.),
+            (v4(0., 0., 1., 1.), 0.),
+        ];
+
+        let (width, height) = (self.width as f64, self.height as f64);
+        let guard_band = [
+            v4(1., 0., 0., GUARD_BAND),
+            v4(-1., 0., 0., width + GUARD_BAND),
+            v4(0., 1., 0., GUARD_BAND),
+            v4(0., -1., 0., height + GUARD_BAND),
+        ];
+
+        clip_space
+            .iter()
+            .map(|&(normal, offset)| ClipPlane {
+                normal: inv_viewport * normal,
+                offset,
+            })
+            .chain(
+                guard_band
+                    .iter()
+                    .map(|&normal| ClipPlane { normal, offset: 0. }),
+            )
+            .collect()
     }
 
-    fn triangle<S: Shader>(&mut self, shader: &mut S, ctx: &RenderContext, face: &Face) {
-        let points: Vec<V4> = (0..3).map(|i| shader.vertex(ctx, face, i)).collect();
+    fn triangle<'a, S: Shader>(
+        &self,
+        shader: &mut S,
+        ctx: &RenderContext,
+        face: Face<'a>,
+        planes: &[ClipPlane],
+        vertices: &mut [Option<(V4, S::Varyings)>],
+        triangles: &mut Vec<Triangle<'a, S::Varyings>>,
+    ) {
+        // Each vertex is shaded once per pass and shared by every face using it
+        let shaded = [0, 1, 2].map(|i| {
+            let index = face.indices[i];
+            *vertices[index].get_or_insert_with(|| shader.vertex(ctx, index, face.verts[i]))
+        });
+        let varyings = shaded.map(|(_, varyings)| varyings);
+
+        let corners = M3::identity();
+        let points: Vec<ClipVertex> = (0..3)
+            .map(|i| ClipVertex {
+                position: shaded[i].0,
+                coords: corners[i],
+            })
+            .collect();
+
+        let polygon = planes
+            .iter()
+            .fold(points, |polygon, plane| plane.clip(polygon));
+
+        for i in 2..polygon.len() {
+            let points = [polygon[0], polygon[i - 1], polygon[i]];
+            if let Some(triangle) = self.setup(face, varyings, points) {
+                triangles.push(triangle);
+            }
+        }
+    }
+
+    fn setup<'a, V: Varying>(
+        &self,
+        face: Face<'a>,
+        varyings: [V; 3],
+        triangle: [ClipVertex; 3],
+    ) -> Option<Triangle<'a, V>> {
+        let face_coords = M3::from_cols(triangle[0].coords, triangle[1].coords, triangle[2].coords);
+        let points: Vec<V4> = triangle.iter().map(|v| v.position).collect();
 
         let points_z = v3(points[0].z, points[1].z, points[2].z);
 
         let points_w = v3(points[0].w, points[1].w, points[2].w);
 
-        let points: Vec<V3> = points
+        let fixed: Vec<[i64; 2]> = points
+            .iter()
+            .map(|p| {
+                let steps = SUBPIXEL_STEPS as f64;
+                [
+                    (p.x / p.w * steps).round() as i64,
+                    (p.y / p.w * steps).round() as i64,
+                ]
+            })
+            .collect();
+        let points: Vec<V2> = fixed
             .iter()
-            .map(|p| v3(p.x / p.w, p.y / p.w, p.z / p.w))
+            .map(|p| v2(p[0] as f64, p[1] as f64) / SUBPIXEL_STEPS as f64)
             .collect();
 
+        // Twice the signed area, triangles that snap to a line cover nothing
+        let area = Edge::new(fixed[1], fixed[2]).at(fixed[0]);
+        if area == 0 {
+            return None;
+        }
+
+        let counter_clockwise = area > 0;
+        let front_facing = counter_clockwise == (self.winding == Winding::CounterClockwise);
+        match (self.cull_mode, front_facing) {
+            (CullMode::Back, false) | (CullMode::Front, true) => return None,
+            _ => (),
+        }
+
         let (bbmin, bbmax) = {
             let clamp = v2((self.width - 1) as f64, (self.height - 1) as f64);
-            let range = (
-                v2(::std::f64::MAX, ::std::f64::MAX),
-                v2(::std::f64::MIN, ::std::f64::MIN),
-            );
+            let range = (v2(f64::MAX, f64::MAX), v2(f64::MIN, f64::MIN));
             let (min, max) = points.iter().fold(range, |mut a, p| {
                 if p.x < a.0.x {
                     a.0.x = p.x;
@@ -89,36 +356,194 @@ impl Renderer {
                 }
                 a
             });
+            // Samples lie within half a pixel of the pixel they belong to
+            let (min, max) = (min - v2(0.5, 0.5), max + v2(0.5, 0.5));
             (
-                v2((0_f64).max(min.x.floor()), (0_f64).max(min.y.floor())),
+                v2((0_f64).max(min.x.ceil()), (0_f64).max(min.y.ceil())),
                 v2(clamp.x.min(max.x.floor()), clamp.y.min(max.y.floor())),
             )
         };
 
-        for p in V2Box::new(bbmin, bbmax) {
-            let coords = barycentric(&points, &p);
-            if coords.x < 0. || coords.y < 0. || coords.z < 0. {
-                continue;
+        // Each edge is opposite the vertex it weights and turned so that the
+        // inside of the triangle is positive.
+        let edges = [0, 1, 2].map(|i| {
+            let (a, b) = (fixed[(i + 1) % 3], fixed[(i + 2) % 3]);
+            if counter_clockwise {
+                Edge::new(a, b)
+            } else {
+                Edge::new(b, a)
             }
+        });
+
+        Some(Triangle {
+            face,
+            varyings,
+            face_coords,
+            front_facing,
+            edges,
+            points_z,
+            points_w,
+            bbmin,
+            bbmax,
+        })
+    }
+
+    fn bin<V: Varying>(&self, triangles: &[Triangle<V>]) -> Vec<Tile> {
+        let tiles_x = self.width.div_ceil(TILE_SIZE);
+        let tiles_y = self.height.div_ceil(TILE_SIZE);
+        let mut bins = vec![Vec::new(); (tiles_x * tiles_y) as usize];
 
-            let clip = v3(
-                coords.x / points_w.x,
-                coords.y / points_w.y,
-                coords.z / points_w.z,
+        for (index, triangle) in triangles.iter().enumerate() {
+            if triangle.bbmin.x > triangle.bbmax.x || triangle.bbmin.y > triangle.bbmax.y {
+                continue;
+            }
+            let (x0, y0) = (
+                triangle.bbmin.x as u32 / TILE_SIZE,
+                triangle.bbmin.y as u32 / TILE_SIZE,
+            );
+            let (x1, y1) = (
+                triangle.bbmax.x as u32 / TILE_SIZE,
+                triangle.bbmax.y as u32 / TILE_SIZE,
             );
+            for y in y0..y1 + 1 {
+                for x in x0..x1 + 1 {
+                    bins[(y * tiles_x + x) as usize].push(index);
+                }
+            }
+        }
 
-            let clip = clip / (clip.x + clip.y + clip.z);
-            let z = points_z.dot(clip);
+        bins.into_iter()
+            .enumerate()
+            .filter(|(_, bin)| !bin.is_empty())
+            .map(|(i, triangles)| {
+                let x = (i as u32 % tiles_x) * TILE_SIZE;
+                let y = (i as u32 / tiles_x) * TILE_SIZE;
+                Tile {
+                    x,
+                    y,
+                    width: TILE_SIZE.min(self.width - x),
+                    height: TILE_SIZE.min(self.height - y),
+                    triangles,
+                }
+            })
+            .collect()
+    }
+
+    fn shade_tile<S: Shader>(
+        &self,
+        shader: &mut S,
+        ctx: &RenderContext,
+        triangles: &[Triangle<S::Varyings>],
+        tile: &Tile,
+    ) -> ShadedTile {
+        let samples = self.samples();
+        let mut color =
+            self.color_samples
+                .region(tile.x * samples, tile.y, tile.width * samples, tile.height);
+        let mut depth =
+            self.depth_samples
+                .region(tile.x * samples, tile.y, tile.width * samples, tile.height);
+
+        for triangle in tile.triangles.iter().map(|&i| &triangles[i]) {
+            let bbmin = v2(
+                triangle.bbmin.x.max(tile.x as f64),
+                triangle.bbmin.y.max(tile.y as f64),
+            );
+            let bbmax = v2(
+                triangle.bbmax.x.min((tile.x + tile.width - 1) as f64),
+                triangle.bbmax.y.min((tile.y + tile.height - 1) as f64),
+            );
+            if bbmin.x > bbmax.x || bbmin.y > bbmax.y {
+                continue;
+            }
 
-            let image_x = p.x as u32;
-            let image_y = p.y as u32;
-            if self.z_buf.get(image_x, image_y) < z {
-                shader.fragment(ctx, clip).map(|c| {
-                    self.display_buf.set(image_x, image_y, c);
-                    self.z_buf.set(image_x, image_y, z);
-                });
+            // Pixels are visited in 2x2 quads starting on even coordinates.
+            // Every pixel of a quad is interpolated whether it is covered or
+            // not, so the differences across it give screen space derivatives.
+            // The edge functions are stepped from the first quad instead of
+            // being evaluated at every pixel.
+            let (x0, y0) = (bbmin.x as u32 & !1, bbmin.y as u32 & !1);
+            let origin = [x0 as i64 * SUBPIXEL_STEPS, y0 as i64 * SUBPIXEL_STEPS];
+            let step_x = triangle.edges.map(|e| e.step([SUBPIXEL_STEPS, 0]));
+            let step_y = triangle.edges.map(|e| e.step([0, SUBPIXEL_STEPS]));
+            let mut row = triangle.edges.map(|e| e.at(origin));
+            for qy in (y0..bbmax.y as u32 + 1).step_by(2) {
+                let mut values = row;
+                row = add_steps(add_steps(row, step_y), step_y);
+                for qx in (x0..bbmax.x as u32 + 1).step_by(2) {
+                    let right = add_steps(values, step_x);
+                    let quad_values = [
+                        values,
+                        right,
+                        add_steps(values, step_y),
+                        add_steps(right, step_y),
+                    ];
+                    values = add_steps(right, step_x);
+
+                    let mut quad = [
+                        triangle.pixel(qx, qy, quad_values[0], &self.samples),
+                        triangle.pixel(qx + 1, qy, quad_values[1], &self.samples),
+                        triangle.pixel(qx, qy + 1, quad_values[2], &self.samples),
+                        triangle.pixel(qx + 1, qy + 1, quad_values[3], &self.samples),
+                    ];
+                    for pixel in quad.iter_mut() {
+                        let (x, y) = (pixel.x as f64, pixel.y as f64);
+                        if x < bbmin.x || x > bbmax.x || y < bbmin.y || y > bbmax.y {
+                            pixel.coverage = 0;
+                        }
+                    }
+                    if quad.iter().all(|pixel| pixel.coverage == 0) {
+                        continue;
+                    }
+
+                    let ddx = triangle.face_coords * (quad[1].clip - quad[0].clip);
+                    let ddy = triangle.face_coords * (quad[2].clip - quad[0].clip);
+                    let ddx = Varying::interpolate(&triangle.varyings, ddx);
+                    let ddy = Varying::interpolate(&triangle.varyings, ddy);
+
+                    for pixel in quad.iter().filter(|pixel| pixel.coverage != 0) {
+                        let tile_x = (pixel.x - tile.x) * samples;
+                        let tile_y = pixel.y - tile.y;
+
+                        // Depth is tested per sample, the shader runs once at
+                        // the pixel center if any of them pass.
+                        let passed = (0..samples)
+                            .filter(|&i| pixel.coverage & (1 << i) != 0)
+                            .filter(|&i| depth.get(tile_x + i, tile_y) < pixel.depth[i as usize])
+                            .fold(0, |mask, i| mask | (1 << i));
+                        if passed == 0 {
+                            continue;
+                        }
+
+                        let coords = triangle.face_coords * pixel.clip;
+                        let frag = Fragment {
+                            coords,
+                            face: triangle.face,
+                            front_facing: triangle.front_facing,
+                            varyings: Varying::interpolate(&triangle.varyings, coords),
+                            ddx,
+                            ddy,
+                        };
+                        if let Some(c) = shader.fragment(ctx, &frag) {
+                            for i in (0..samples).filter(|&i| passed & (1 << i) != 0) {
+                                let dst = color.get(tile_x + i, tile_y);
+                                color.set(tile_x + i, tile_y, self.blend.blend(c, dst));
+                                if self.depth_write {
+                                    depth.set(tile_x + i, tile_y, pixel.depth[i as usize]);
+                                }
+                            }
+                        }
+                    }
+                }
             }
         }
+
+        ShadedTile {
+            x: tile.x,
+            y: tile.y,
+            color,
+            depth,
+        }
     }
 
     pub fn viewport(&mut self, x: f64, y: f64, width: f64, height: f64) {
@@ -134,112 +559,527 @@ impl Renderer {
         self.viewport = viewport;
     }
 
-    pub fn projection(&mut self, camera_distance: f64) {
-        let mut projection = M4::identity();
-        projection[2][3] = camera_distance;
+    pub fn perspective(&mut self, fov_y: f64, aspect: f64, near: f64, far: f64) {
+        self.projection = perspective(fov_y, aspect, near, far);
+    }
 
-        self.projection = projection;
+    pub fn orthographic(
+        &mut self,
+        left: f64,
+        right: f64,
+        bottom: f64,
+        top: f64,
+        near: f64,
+        far: f64,
+    ) {
+        self.projection = orthographic(left, right, bottom, top, near, far);
     }
 
     pub fn lookat(&mut self, eye: V3, center: V3, up: V3) {
-        let z = (eye - center).normalize();
-        let x = up.cross(z).normalize();
-        let y = z.cross(x).normalize();
-        let mut modelview = M4::identity();
-        for i in 0..3 {
-            modelview[i][0] = x[i];
-            modelview[i][1] = y[i];
-            modelview[i][2] = z[i];
-            modelview[3][i] = -center[i];
-        }
+        self.modelview = lookat(eye, center, up);
+    }
 
-        self.modelview = modelview;
+    pub fn camera(&mut self, camera: &Camera) {
+        self.modelview = camera.view_matrix();
+        self.projection = camera.projection_matrix();
     }
 }
 
-pub fn matrix_transform(v: V3, m: M4) -> V3 {
-    let v = m * v.extend(1.);
-    v3(v.x / v.w, v.y / v.w, v.z / v.w)
+const W_EPSILON: f64 = 1e-5;
+const TILE_SIZE: u32 = 64;
+const MAX_SAMPLES: usize = 16;
+
+// Rasterization happens on vertices snapped to 1 / SUBPIXEL_STEPS of a pixel
+const SUBPIXEL_STEPS: i64 = 256;
+// Pixels past the screen edges that triangles are allowed to reach
+const GUARD_BAND: f64 = 65536.;
+
+// Fixed point sample positions from the pixel center, the patterns are
+// given in sixteenths of a pixel.
+fn sample_pattern(samples: u32) -> Vec<[i64; 2]> {
+    let pattern: &[(i8, i8)] = match samples {
+        0..=1 => &[(0, 0)],
+        2..=3 => &[(4, 4), (-4, -4)],
+        4..=7 => &[(-2, -6), (6, -2), (-6, 2), (2, 6)],
+        8..=15 => &[
+            (1, -3),
+            (-1, 3),
+            (5, 1),
+            (-3, -5),
+            (-5, 5),
+            (-7, -1),
+            (3, 7),
+            (7, -7),
+        ],
+        _ => &[
+            (1, 1),
+            (-1, -3),
+            (-3, 2),
+            (4, -1),
+            (-5, -2),
+            (2, 5),
+            (5, 3),
+            (3, -5),
+            (-2, 6),
+            (0, -7),
+            (-4, -6),
+            (-6, 4),
+            (-8, 0),
+            (7, -4),
+            (6, 7),
+            (-7, -8),
+        ],
+    };
+
+    pattern
+        .iter()
+        .map(|&(x, y)| {
+            [
+                x as i64 * SUBPIXEL_STEPS / 16,
+                y as i64 * SUBPIXEL_STEPS / 16,
+            ]
+        })
+        .collect()
 }
 
-fn barycentric(tri: &Vec<V3>, p: &V2) -> V3 {
-    let u = v3(tri[2].x - tri[0].x, tri[1].x - tri[0].x, tri[0].x - p.x).cross(v3(
-        tri[2].y - tri[0].y,
-        tri[1].y - tri[0].y,
-        tri[0].y - p.y,
-    ));
+// The edge from a to b as a function that is positive on its left, zero on
+// the edge and negative on its right. Only top and left edges own the
+// pixels exactly on them so triangles sharing an edge never both draw it.
+#[derive(Copy, Clone)]
+struct Edge {
+    dx: i64,
+    dy: i64,
+    c: i64,
+    bias: i64,
+}
 
-    if u.z.abs() < 1. {
-        v3(-1., 1., 1.)
-    } else {
-        v3(1. - (u.x + u.y) / u.z, u.y / u.z, u.x / u.z)
+impl Edge {
+    fn new(a: [i64; 2], b: [i64; 2]) -> Edge {
+        let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+        // With the inside on the left, left edges point down and top edges
+        // point left
+        let top_left = dy < 0 || (dy == 0 && dx < 0);
+
+        Edge {
+            dx,
+            dy,
+            c: dy * a[0] - dx * a[1],
+            bias: if top_left { 0 } else { -1 },
+        }
     }
+
+    fn at(&self, p: [i64; 2]) -> i64 {
+        self.dx * p[1] - self.dy * p[0] + self.c
+    }
+
+    // The change in value across a fixed point offset
+    fn step(&self, offset: [i64; 2]) -> i64 {
+        self.dx * offset[1] - self.dy * offset[0]
+    }
+
+    fn inside(&self, value: i64) -> bool {
+        value + self.bias >= 0
+    }
+}
+
+fn add_steps(values: [i64; 3], steps: [i64; 3]) -> [i64; 3] {
+    [
+        values[0] + steps[0],
+        values[1] + steps[1],
+        values[2] + steps[2],
+    ]
 }
 
-struct V2Box {
-    start: V2,
-    end: V2,
-    cur: V2,
-    done: bool,
+struct Triangle<'a, V> {
+    face: Face<'a>,
+    varyings: [V; 3],
+    face_coords: M3,
+    front_facing: bool,
+    edges: [Edge; 3],
+    points_z: V3,
+    points_w: V3,
+    bbmin: V2,
+    bbmax: V2,
 }
 
-impl V2Box {
-    fn new(start: V2, end: V2) -> V2Box {
-        let mut cur = start;
-        let mut done = false;
-        if cur.y > end.y {
-            cur.y = start.y;
-            cur.x += 1.;
-            if cur.x > end.x {
-                done = true;
+impl<'a, V> Triangle<'a, V> {
+    // `values` are the edge functions at the pixel center
+    fn pixel(&self, x: u32, y: u32, values: [i64; 3], samples: &[[i64; 2]]) -> QuadPixel {
+        let mut pixel = QuadPixel {
+            x,
+            y,
+            coverage: 0,
+            depth: [f64::MIN; MAX_SAMPLES],
+            clip: self.weights(values),
+        };
+
+        for (i, &offset) in samples.iter().enumerate() {
+            let values = add_steps(values, self.edges.map(|e| e.step(offset)));
+            if (0..3).all(|e| self.edges[e].inside(values[e])) {
+                pixel.coverage |= 1 << i;
+                pixel.depth[i] = self.points_z.dot(self.weights(values));
             }
         }
-        V2Box {
-            start: start,
-            end: end,
-            cur: cur,
-            done: done,
+
+        pixel
+    }
+
+    // Perspective correct barycentric coordinates from the edge functions,
+    // each of which is proportional to the weight of its opposite vertex.
+    fn weights(&self, values: [i64; 3]) -> V3 {
+        let clip = v3(
+            values[0] as f64 / self.points_w.x,
+            values[1] as f64 / self.points_w.y,
+            values[2] as f64 / self.points_w.z,
+        );
+
+        clip / (clip.x + clip.y + clip.z)
+    }
+}
+
+// Pixels outside the triangle still get perspective correct barycentric
+// coordinates, they just lie outside of 0..1. Coverage has a bit for each
+// sample inside the triangle and depth holds the depth at those samples.
+struct QuadPixel {
+    x: u32,
+    y: u32,
+    coverage: u32,
+    depth: [f64; MAX_SAMPLES],
+    clip: V3,
+}
+
+struct Tile {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    triangles: Vec<usize>,
+}
+
+struct ShadedTile {
+    x: u32,
+    y: u32,
+    color: Texture<V4>,
+    depth: Texture<f64>,
+}
+
+#[derive(Copy, Clone)]
+struct ClipVertex {
+    position: V4,
+    coords: V3,
+}
+
+impl ClipVertex {
+    fn lerp(&self, other: &ClipVertex, t: f64) -> ClipVertex {
+        ClipVertex {
+            position: self.position + (other.position - self.position) * t,
+            coords: self.coords + (other.coords - self.coords) * t,
         }
     }
 }
 
-impl Iterator for V2Box {
-    type Item = V2;
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.done {
-            return None;
+struct ClipPlane {
+    normal: V4,
+    offset: f64,
+}
+
+impl ClipPlane {
+    fn distance(&self, v: &ClipVertex) -> f64 {
+        self.normal.dot(v.position) - self.offset
+    }
+
+    fn clip(&self, polygon: Vec<ClipVertex>) -> Vec<ClipVertex> {
+        if polygon.iter().all(|v| self.distance(v) >= 0.) {
+            return polygon;
         }
-        let next = self.cur;
-        self.cur.y += 1.;
-        if self.cur.y > self.end.y {
-            self.cur.y = self.start.y;
-            self.cur.x += 1.;
-            if self.cur.x > self.end.x {
-                self.done = true;
+
+        let mut clipped = Vec::with_capacity(polygon.len() + 1);
+        for (i, a) in polygon.iter().enumerate() {
+            let b = &polygon[(i + 1) % polygon.len()];
+            let (da, db) = (self.distance(a), self.distance(b));
+            if da >= 0. {
+                clipped.push(*a);
+            }
+            if (da >= 0.) != (db >= 0.) {
+                clipped.push(a.lerp(b, da / (da - db)));
             }
         }
-        Some(next)
+
+        clipped
     }
 }
 
+pub fn lookat(eye: V3, center: V3, up: V3) -> M4 {
+    let z = (eye - center).normalize();
+    let x = up.cross(z).normalize();
+    let y = z.cross(x).normalize();
+    let mut modelview = M4::identity();
+    for i in 0..3 {
+        modelview[i][0] = x[i];
+        modelview[i][1] = y[i];
+        modelview[i][2] = z[i];
+    }
+    modelview[3][0] = -x.dot(eye);
+    modelview[3][1] = -y.dot(eye);
+    modelview[3][2] = -z.dot(eye);
+
+    modelview
+}
+
+// Both projections map the near plane to +1 and the far plane to -1, which
+// keeps greater depth values closer to the camera for the depth test.
+pub fn perspective(fov_y: f64, aspect: f64, near: f64, far: f64) -> M4 {
+    let f = 1. / (fov_y / 2.).tan();
+    let mut projection = M4::identity();
+    projection[0][0] = f / aspect;
+    projection[1][1] = f;
+    projection[2][2] = (far + near) / (far - near);
+    projection[3][2] = 2. * far * near / (far - near);
+    projection[2][3] = -1.;
+    projection[3][3] = 0.;
+
+    projection
+}
+
+pub fn orthographic(left: f64, right: f64, bottom: f64, top: f64, near: f64, far: f64) -> M4 {
+    let mut projection = M4::identity();
+    projection[0][0] = 2. / (right - left);
+    projection[1][1] = 2. / (top - bottom);
+    projection[2][2] = 2. / (far - near);
+    projection[3][0] = -(right + left) / (right - left);
+    projection[3][1] = -(top + bottom) / (top - bottom);
+    projection[3][2] = (far + near) / (far - near);
+
+    projection
+}
+
+pub fn normal_matrix(modelview: M4) -> M3 {
+    let m = modelview.transpose().invert().unwrap_or_else(M4::identity);
+    M3::from_cols(m.x.truncate(), m.y.truncate(), m.z.truncate())
+}
+
+pub fn matrix_transform(v: V3, m: M4) -> V3 {
+    let v = m * v.extend(1.);
+    v3(v.x / v.w, v.y / v.w, v.z / v.w)
+}
+
+// `modelview` is `view * model_matrix`, `normal_matrix` carries normals
+// into the same view space.
 pub struct RenderContext<'a> {
     pub viewport: M4,
     pub projection: M4,
+    pub view: M4,
+    pub model_matrix: M4,
     pub modelview: M4,
+    pub normal_matrix: M3,
     pub model: &'a Model,
+    pub lights: &'a [Light],
+}
+
+impl<'a> RenderContext<'a> {
+    pub fn material(&self, index: usize) -> &'a Material {
+        self.model.material(index)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CullMode {
+    None,
+    Back,
+    Front,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Winding {
+    Clockwise,
+    CounterClockwise,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BlendFactor {
+    Zero,
+    One,
+    SrcColor,
+    OneMinusSrcColor,
+    DstColor,
+    OneMinusDstColor,
+    SrcAlpha,
+    OneMinusSrcAlpha,
+    DstAlpha,
+    OneMinusDstAlpha,
+}
+
+impl BlendFactor {
+    fn factor(self, src: V4, dst: V4) -> V4 {
+        let one = v4(1., 1., 1., 1.);
+        match self {
+            BlendFactor::Zero => v4(0., 0., 0., 0.),
+            BlendFactor::One => one,
+            BlendFactor::SrcColor => src,
+            BlendFactor::OneMinusSrcColor => one - src,
+            BlendFactor::DstColor => dst,
+            BlendFactor::OneMinusDstColor => one - dst,
+            BlendFactor::SrcAlpha => one * src.w,
+            BlendFactor::OneMinusSrcAlpha => one * (1. - src.w),
+            BlendFactor::DstAlpha => one * dst.w,
+            BlendFactor::OneMinusDstAlpha => one * (1. - dst.w),
+        }
+    }
 }
 
-pub trait Shader {
+// Min and Max compare the colors themselves and ignore the factors
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BlendOp {
+    Add,
+    Subtract,
+    ReverseSubtract,
+    Min,
+    Max,
+}
+
+impl BlendOp {
+    fn apply(self, src: f64, dst: f64, src_factor: f64, dst_factor: f64) -> f64 {
+        match self {
+            BlendOp::Add => src * src_factor + dst * dst_factor,
+            BlendOp::Subtract => src * src_factor - dst * dst_factor,
+            BlendOp::ReverseSubtract => dst * dst_factor - src * src_factor,
+            BlendOp::Min => src.min(dst),
+            BlendOp::Max => src.max(dst),
+        }
+    }
+}
+
+// Color and alpha are blended separately, `src` is the fragment and `dst` is
+// what the buffer already holds.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BlendState {
+    pub color_src: BlendFactor,
+    pub color_dst: BlendFactor,
+    pub color_op: BlendOp,
+    pub alpha_src: BlendFactor,
+    pub alpha_dst: BlendFactor,
+    pub alpha_op: BlendOp,
+}
+
+impl BlendState {
+    pub const REPLACE: BlendState =
+        BlendState::new(BlendFactor::One, BlendFactor::Zero, BlendOp::Add);
+    pub const ALPHA: BlendState = BlendState {
+        alpha_src: BlendFactor::One,
+        ..BlendState::new(
+            BlendFactor::SrcAlpha,
+            BlendFactor::OneMinusSrcAlpha,
+            BlendOp::Add,
+        )
+    };
+    pub const ADDITIVE: BlendState =
+        BlendState::new(BlendFactor::SrcAlpha, BlendFactor::One, BlendOp::Add);
+
+    pub const fn new(src: BlendFactor, dst: BlendFactor, op: BlendOp) -> BlendState {
+        BlendState {
+            color_src: src,
+            color_dst: dst,
+            color_op: op,
+            alpha_src: src,
+            alpha_dst: dst,
+            alpha_op: op,
+        }
+    }
+
+    pub fn blend(&self, src: V4, dst: V4) -> V4 {
+        let color_src = self.color_src.factor(src, dst);
+        let color_dst = self.color_dst.factor(src, dst);
+        let alpha_src = self.alpha_src.factor(src, dst);
+        let alpha_dst = self.alpha_dst.factor(src, dst);
+
+        v4(
+            self.color_op.apply(src.x, dst.x, color_src.x, color_dst.x),
+            self.color_op.apply(src.y, dst.y, color_src.y, color_dst.y),
+            self.color_op.apply(src.z, dst.z, color_src.z, color_dst.z),
+            self.alpha_op.apply(src.w, dst.w, alpha_src.w, alpha_dst.w),
+        )
+    }
+}
+
+impl Default for BlendState {
+    fn default() -> BlendState {
+        BlendState::REPLACE
+    }
+}
+
+// `ddx` and `ddy` are how much the varyings change to the next pixel right
+// and down, taken across the 2x2 quad the fragment was shaded in.
+pub struct Fragment<'a, V> {
+    pub coords: V3,
+    pub face: Face<'a>,
+    pub front_facing: bool,
+    pub varyings: V,
+    pub ddx: V,
+    pub ddy: V,
+}
+
+// Whatever vertex returns alongside the position reaches fragment already
+// interpolated, the coords it is weighted by are perspective correct.
+pub trait Shader: Clone + Send {
+    type Varyings: Varying;
+
     fn prepare(&mut self, ctx: &RenderContext);
-    fn vertex(&mut self, ctx: &RenderContext, face: &Face, vert: usize) -> V4;
-    fn fragment(&mut self, ctx: &RenderContext, coords: V3) -> Option<V3>;
+    fn vertex(
+        &mut self,
+        ctx: &RenderContext,
+        index: usize,
+        vertex: &Vertex,
+    ) -> (V4, Self::Varyings);
+    fn fragment(&mut self, ctx: &RenderContext, frag: &Fragment<Self::Varyings>) -> Option<V4>;
 }
 
+pub trait Varying: Copy + Send + Sync {
+    fn interpolate(values: &[Self; 3], coords: V3) -> Self;
+}
+
+impl Varying for () {
+    fn interpolate(_values: &[(); 3], _coords: V3) {}
+}
+
+macro_rules! varying_vector {
+    ($($t:ty),*) => {$(
+        impl Varying for $t {
+            fn interpolate(values: &[$t; 3], coords: V3) -> $t {
+                values[0] * coords.x + values[1] * coords.y + values[2] * coords.z
+            }
+        }
+    )*};
+}
+
+varying_vector!(f64, V2, V3, V4);
+
+macro_rules! varying_tuple {
+    ($($name:ident $index:tt),*) => {
+        impl<$($name: Varying),*> Varying for ($($name,)*) {
+            fn interpolate(values: &[Self; 3], coords: V3) -> Self {
+                ($($name::interpolate(
+                    &[values[0].$index, values[1].$index, values[2].$index],
+                    coords,
+                ),)*)
+            }
+        }
+    };
+}
+
+varying_tuple!(A 0);
+varying_tuple!(A 0, B 1);
+varying_tuple!(A 0, B 1, C 2);
+varying_tuple!(A 0, B 1, C 2, D 3);
+varying_tuple!(A 0, B 1, C 2, D 3, E 4);
+varying_tuple!(A 0, B 1, C 2, D 3, E 4, F 5);
+
 #[derive(Clone)]
 pub struct Texture<T> {
     pixels: Vec<T>,
     width: u32,
     height: u32,
+    mips: Vec<Texture<T>>,
+    config: SamplerConfig<T>,
 }
 
 impl<T: Copy> Texture<T> {
@@ -251,6 +1091,51 @@ impl<T: Copy> Texture<T> {
             pixels,
             width: w,
             height: h,
+            mips: Vec::new(),
+            config: SamplerConfig::new(AddressMode::ClampToEdge, default),
+        }
+    }
+
+    // How get_f and get_lod address texels outside of the texture
+    pub fn sampler(&mut self, config: SamplerConfig<T>) {
+        self.config = config;
+    }
+
+    // Levels past the end of the mip chain are its last level, a texture
+    // without mips only has itself.
+    fn level(&self, level: u32) -> &Texture<T> {
+        match level.checked_sub(1) {
+            None => self,
+            Some(i) => self
+                .mips
+                .get(i as usize)
+                .or_else(|| self.mips.last())
+                .unwrap_or(self),
+        }
+    }
+
+    fn region(&self, x: u32, y: u32, w: u32, h: u32) -> Texture<T> {
+        let mut pixels = Vec::with_capacity((w * h) as usize);
+        for row in y..y + h {
+            let start = (row * self.width + x) as usize;
+            pixels.extend_from_slice(&self.pixels[start..start + w as usize]);
+        }
+
+        Texture {
+            pixels,
+            width: w,
+            height: h,
+            mips: Vec::new(),
+            config: self.config,
+        }
+    }
+
+    fn blit(&mut self, x: u32, y: u32, src: &Texture<T>) {
+        for row in 0..src.height {
+            let start = ((y + row) * self.width + x) as usize;
+            let src_start = (row * src.width) as usize;
+            self.pixels[start..start + src.width as usize]
+                .copy_from_slice(&src.pixels[src_start..src_start + src.width as usize]);
         }
     }
 
@@ -289,10 +1174,51 @@ impl<T: Copy> Texture<T> {
     }
 }
 
+impl<T> Texture<T>
+where
+    T: Copy + ::std::ops::Mul<f64, Output = T> + ::std::ops::Add<T, Output = T>,
+{
+    // Each level is a box filtered half of the one above it, down to 1x1.
+    // Odd sized levels repeat their last row or column.
+    pub fn build_mips(&mut self) {
+        let mut mips: Vec<Texture<T>> = Vec::new();
+        loop {
+            let src = mips.last().unwrap_or(self);
+            if src.width == 1 && src.height == 1 {
+                break;
+            }
+
+            let (width, height) = ((src.width / 2).max(1), (src.height / 2).max(1));
+            let mut pixels = Vec::with_capacity((width * height) as usize);
+            for y in 0..height {
+                for x in 0..width {
+                    let (x0, y0) = (x * 2, y * 2);
+                    let (x1, y1) = ((x0 + 1).min(src.width - 1), (y0 + 1).min(src.height - 1));
+                    let sum = src.get(x0, y0) + src.get(x1, y0) + src.get(x0, y1) + src.get(x1, y1);
+                    pixels.push(sum * 0.25);
+                }
+            }
+
+            mips.push(Texture {
+                pixels,
+                width,
+                height,
+                mips: Vec::new(),
+                config: self.config,
+            });
+        }
+
+        self.mips = mips;
+    }
+}
+
 impl Texture<V4> {
-    pub fn from_file<P: AsRef<::std::path::Path>>(path: P) -> Texture<V4> {
+    pub fn from_file<P: AsRef<::std::path::Path>>(path: P) -> Result<Texture<V4>> {
         use image::Pixel;
-        let img = image::open(path).unwrap().to_rgba();
+        let path = path.as_ref();
+        let img = image::open(path)
+            .map_err(|e| Error::image(path, e))?
+            .to_rgba();
         let (width, height) = img.dimensions();
         let mut pixels = Vec::with_capacity((width * height) as usize);
         for y in 0..height {
@@ -304,21 +1230,27 @@ impl Texture<V4> {
                     b as f64 / 255.,
                     a as f64 / 255.,
                 );
-                pixels.push(c.into());
+                pixels.push(c);
             }
         }
 
-        Texture {
+        let mut texture = Texture {
             pixels,
             width,
             height,
-        }
+            mips: Vec::new(),
+            config: SamplerConfig::default(),
+        };
+        texture.build_mips();
+
+        Ok(texture)
     }
 }
 
 impl<T: Into<Color> + Clone + Copy> Texture<T> {
-    fn write<P: AsRef<::std::path::Path>>(&self, path: P) -> ::std::io::Result<()> {
+    pub fn write<P: AsRef<::std::path::Path>>(&self, path: P) -> Result<()> {
         use image::{imageops, ImageBuffer, ImageRgba8, Pixel, Rgba};
+        let path = path.as_ref();
         let mut buf = ImageBuffer::new(self.width, self.height);
 
         for (x, y, p) in buf.enumerate_pixels_mut() {
@@ -327,9 +1259,10 @@ impl<T: Into<Color> + Clone + Copy> Texture<T> {
         }
 
         let buf = imageops::flip_vertical(&buf);
-        let mut file = ::std::fs::File::create(path)?;
-        let _ = ImageRgba8(buf).save(&mut file, image::PNG);
-        Ok(())
+        let mut file = ::std::fs::File::create(path).map_err(|e| Error::io(path, e))?;
+        ImageRgba8(buf)
+            .save(&mut file, image::PNG)
+            .map_err(|e| Error::image(path, e))
     }
 }
 
@@ -347,10 +1280,7 @@ impl<T: Copy> Surface for Texture<T> {
     }
 
     fn get_f(&self, x: f64, y: f64) -> T {
-        self.get(
-            (x * (self.width - 1) as f64) as u32,
-            (y * (self.height - 1) as f64) as u32,
-        )
+        nearest(self, &self.config, 0, x, y)
     }
 
     fn set(&mut self, x: u32, y: u32, color: T) {
@@ -359,8 +1289,32 @@ impl<T: Copy> Surface for Texture<T> {
             self.pixels[ind] = color;
         }
     }
+
+    fn sampler_config(&self) -> SamplerConfig<T> {
+        self.config
+    }
+
+    fn levels(&self) -> u32 {
+        self.mips.len() as u32 + 1
+    }
+
+    fn level_size(&self, level: u32) -> (u32, u32) {
+        let level = self.level(level);
+        (level.width, level.height)
+    }
+
+    fn get_level(&self, level: u32, x: u32, y: u32) -> T {
+        self.level(level).get(x, y)
+    }
+
+    fn get_lod(&self, x: f64, y: f64, lod: f64) -> T {
+        let level = nearest_level(lod, self.levels());
+        nearest(self, &self.config, level, x, y)
+    }
 }
 
+// Level 0 is the surface itself, every level after it is half the size of
+// the one before. Surfaces without mips only have level 0.
 pub trait Surface {
     type Item;
     fn width(&self) -> u32;
@@ -368,21 +1322,171 @@ pub trait Surface {
     fn get(&self, x: u32, y: u32) -> Self::Item;
     fn get_f(&self, x: f64, y: f64) -> Self::Item;
     fn set(&mut self, x: u32, y: u32, value: Self::Item);
+    // How get_f and get_lod address texels outside of the surface, samplers
+    // wrapping it start from the same config.
+    fn sampler_config(&self) -> SamplerConfig<Self::Item>;
+
+    fn levels(&self) -> u32 {
+        1
+    }
+
+    fn level_size(&self, level: u32) -> (u32, u32) {
+        (
+            (self.width() >> level).max(1),
+            (self.height() >> level).max(1),
+        )
+    }
+
+    fn get_level(&self, _level: u32, x: u32, y: u32) -> Self::Item {
+        self.get(x, y)
+    }
+
+    // Samples with an explicit level of detail instead of level 0
+    fn get_lod(&self, x: f64, y: f64, _lod: f64) -> Self::Item {
+        self.get_f(x, y)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum AddressMode {
+    Repeat,
+    MirroredRepeat,
+    #[default]
+    ClampToEdge,
+    ClampToBorder,
 }
 
-pub struct BilinearSampler<T> {
+impl AddressMode {
+    // The texel a possibly out of range index reads from, None for the border
+    fn resolve(self, i: i64, size: u32) -> Option<u32> {
+        let size = size as i64;
+        let i = match self {
+            AddressMode::Repeat => i.rem_euclid(size),
+            AddressMode::MirroredRepeat => {
+                let i = i.rem_euclid(2 * size);
+                if i < size {
+                    i
+                } else {
+                    2 * size - 1 - i
+                }
+            }
+            AddressMode::ClampToEdge => i.max(0).min(size - 1),
+            AddressMode::ClampToBorder if i < 0 || i >= size => return None,
+            AddressMode::ClampToBorder => i,
+        };
+
+        Some(i as u32)
+    }
+}
+
+// Coordinates are in 0..1 across the surface with texel centers at
+// (i + 0.5) / size, anything outside is resolved by the address modes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SamplerConfig<T> {
+    pub address_u: AddressMode,
+    pub address_v: AddressMode,
+    pub border: T,
+}
+
+impl<T: Copy> SamplerConfig<T> {
+    pub fn new(address: AddressMode, border: T) -> SamplerConfig<T> {
+        SamplerConfig {
+            address_u: address,
+            address_v: address,
+            border,
+        }
+    }
+
+    fn texel<S>(&self, surface: &S, level: u32, x: i64, y: i64) -> T
+    where
+        S: Surface<Item = T> + ?Sized,
+    {
+        let (width, height) = surface.level_size(level);
+        match (
+            self.address_u.resolve(x, width),
+            self.address_v.resolve(y, height),
+        ) {
+            (Some(x), Some(y)) => surface.get_level(level, x, y),
+            _ => self.border,
+        }
+    }
+}
+
+impl<T: Zero> Default for SamplerConfig<T> {
+    fn default() -> SamplerConfig<T> {
+        SamplerConfig {
+            address_u: AddressMode::default(),
+            address_v: AddressMode::default(),
+            border: T::zero(),
+        }
+    }
+}
+
+fn nearest_level(lod: f64, levels: u32) -> u32 {
+    lod.round().max(0.).min((levels - 1) as f64) as u32
+}
+
+fn nearest<S, U>(surface: &S, config: &SamplerConfig<U>, level: u32, x: f64, y: f64) -> U
+where
+    S: Surface<Item = U> + ?Sized,
+    U: Copy,
+{
+    let (width, height) = surface.level_size(level);
+    let x = (x * width as f64).floor() as i64;
+    let y = (y * height as f64).floor() as i64;
+
+    config.texel(surface, level, x, y)
+}
+
+fn bilinear<S, U>(surface: &S, config: &SamplerConfig<U>, level: u32, x: f64, y: f64) -> U
+where
+    S: Surface<Item = U> + ?Sized,
+    U: Copy + ::std::ops::Mul<f64, Output = U> + ::std::ops::Add<U, Output = U>,
+{
+    let (width, height) = surface.level_size(level);
+    let x = x * width as f64 - 0.5;
+    let y = y * height as f64 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as i64, y0 as i64);
+
+    let v0 = config.texel(surface, level, x0, y0);
+    let v1 = config.texel(surface, level, x0 + 1, y0);
+    let r0 = v0 * (1. - tx) + v1 * tx;
+
+    let v0 = config.texel(surface, level, x0, y0 + 1);
+    let v1 = config.texel(surface, level, x0 + 1, y0 + 1);
+    let r1 = v0 * (1. - tx) + v1 * tx;
+
+    r0 * (1. - ty) + r1 * ty
+}
+
+pub struct BilinearSampler<T: Surface> {
     inner: T,
+    config: SamplerConfig<T::Item>,
 }
 
-impl<T: Surface> BilinearSampler<T> {
+impl<T: Surface> BilinearSampler<T>
+where
+    T::Item: Copy,
+{
+    // Samples with the config the image already has
     pub fn new(image: T) -> BilinearSampler<T> {
-        BilinearSampler { inner: image }
+        let config = image.sampler_config();
+        Self::with_config(image, config)
+    }
+
+    pub fn with_config(image: T, config: SamplerConfig<T::Item>) -> BilinearSampler<T> {
+        BilinearSampler {
+            inner: image,
+            config,
+        }
     }
 }
 
 impl<
         T: Surface<Item = U>,
-        U: ::std::ops::Mul<f64, Output = U> + ::std::ops::Add<U, Output = U>,
+        U: Copy + ::std::ops::Mul<f64, Output = U> + ::std::ops::Add<U, Output = U>,
     > Surface for BilinearSampler<T>
 {
     type Item = T::Item;
@@ -396,29 +1500,125 @@ impl<
         self.inner.get(x, y)
     }
     fn get_f(&self, x: f64, y: f64) -> T::Item {
-        let x = x.max(0.0).min(1.0) * (self.width() - 1) as f64;
-        let y = y.max(0.0).min(1.0) * (self.height() - 1) as f64;
-        let x0 = x.floor();
-        let x1 = x.ceil();
-        let y0 = y.floor();
-        let y1 = y.ceil();
+        bilinear(&self.inner, &self.config, 0, x, y)
+    }
+    fn set(&mut self, x: u32, y: u32, value: T::Item) {
+        self.inner.set(x, y, value);
+    }
+    fn sampler_config(&self) -> SamplerConfig<T::Item> {
+        self.config
+    }
+    fn levels(&self) -> u32 {
+        self.inner.levels()
+    }
+    fn level_size(&self, level: u32) -> (u32, u32) {
+        self.inner.level_size(level)
+    }
+    fn get_level(&self, level: u32, x: u32, y: u32) -> T::Item {
+        self.inner.get_level(level, x, y)
+    }
+    fn get_lod(&self, x: f64, y: f64, lod: f64) -> T::Item {
+        let level = nearest_level(lod, self.levels());
+        bilinear(&self.inner, &self.config, level, x, y)
+    }
+}
+
+// Bilinear within the two levels around the level of detail and linear
+// between them.
+pub struct TrilinearSampler<T: Surface> {
+    inner: T,
+    config: SamplerConfig<T::Item>,
+}
 
-        let t = x - x0;
-        let v0 = self.inner.get(x0 as u32, y0 as u32);
-        let v1 = self.inner.get(x1 as u32, y0 as u32);
-        let r0 = v0 * (1. - t) + v1 * t;
+impl<T: Surface> TrilinearSampler<T>
+where
+    T::Item: Copy,
+{
+    // Samples with the config the image already has
+    pub fn new(image: T) -> TrilinearSampler<T> {
+        let config = image.sampler_config();
+        Self::with_config(image, config)
+    }
 
-        let v0 = self.inner.get(x0 as u32, y1 as u32);
-        let v1 = self.inner.get(x1 as u32, y1 as u32);
-        let r1 = v0 * (1. - t) + v1 * t;
+    pub fn with_config(image: T, config: SamplerConfig<T::Item>) -> TrilinearSampler<T> {
+        TrilinearSampler {
+            inner: image,
+            config,
+        }
+    }
+}
 
-        let t = y - y0;
+impl<T, U> TrilinearSampler<T>
+where
+    T: Surface<Item = U>,
+    U: Copy + ::std::ops::Mul<f64, Output = U> + ::std::ops::Add<U, Output = U>,
+{
+    // The level a texel step of ddx or ddy in uv space lands on, the
+    // derivatives are per screen pixel.
+    pub fn lod(&self, ddx: V2, ddy: V2) -> f64 {
+        let size = v2(self.inner.width() as f64, self.inner.height() as f64);
+        let ddx = ddx.mul_element_wise(size);
+        let ddy = ddy.mul_element_wise(size);
+        let rho = ddx.magnitude2().max(ddy.magnitude2());
+
+        if rho.is_finite() && rho > 0. {
+            0.5 * rho.log2()
+        } else {
+            0.
+        }
+    }
 
-        r0 * (1. - t) + r1 * t
+    pub fn get_grad(&self, x: f64, y: f64, ddx: V2, ddy: V2) -> U {
+        self.get_lod(x, y, self.lod(ddx, ddy))
+    }
+}
+
+impl<
+        T: Surface<Item = U>,
+        U: Copy + ::std::ops::Mul<f64, Output = U> + ::std::ops::Add<U, Output = U>,
+    > Surface for TrilinearSampler<T>
+{
+    type Item = T::Item;
+    fn width(&self) -> u32 {
+        self.inner.width()
+    }
+    fn height(&self) -> u32 {
+        self.inner.height()
+    }
+    fn get(&self, x: u32, y: u32) -> T::Item {
+        self.inner.get(x, y)
+    }
+    fn get_f(&self, x: f64, y: f64) -> T::Item {
+        bilinear(&self.inner, &self.config, 0, x, y)
     }
     fn set(&mut self, x: u32, y: u32, value: T::Item) {
         self.inner.set(x, y, value);
     }
+    fn sampler_config(&self) -> SamplerConfig<T::Item> {
+        self.config
+    }
+    fn levels(&self) -> u32 {
+        self.inner.levels()
+    }
+    fn level_size(&self, level: u32) -> (u32, u32) {
+        self.inner.level_size(level)
+    }
+    fn get_level(&self, level: u32, x: u32, y: u32) -> T::Item {
+        self.inner.get_level(level, x, y)
+    }
+    fn get_lod(&self, x: f64, y: f64, lod: f64) -> T::Item {
+        let lod = lod.max(0.).min((self.levels() - 1) as f64);
+        let (level0, level1) = (lod.floor(), lod.ceil());
+        let t = lod - level0;
+
+        let r0 = bilinear(&self.inner, &self.config, level0 as u32, x, y);
+        if t == 0. {
+            return r0;
+        }
+        let r1 = bilinear(&self.inner, &self.config, level1 as u32, x, y);
+
+        r0 * (1. - t) + r1 * t
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -479,7 +1679,7 @@ impl Color {
                 ((c[i] + 0.055) / 1.055).powf(2.4)
             };
 
-            c[i] = c[i].max(0.0).min(1.0);
+            c[i] = c[i].clamp(0.0, 1.0);
         }
 
         Color::from_argb_f(a, c.x, c.y, c.z)
@@ -496,7 +1696,7 @@ impl Color {
                 1.055 * c[i].powf(0.41666) - 0.055
             };
 
-            c[i] = c[i].max(0.0).min(1.0);
+            c[i] = c[i].clamp(0.0, 1.0);
         }
 
         Color::from_argb_f(a, c.x, c.y, c.z)
@@ -541,3 +1741,186 @@ impl From<V4> for Color {
         Color::from_argb_f(v.w, v.x, v.y, v.z)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::tests::parse;
+
+    // Positions are given in pixels, the color follows them so every
+    // triangle shades differently.
+    #[derive(Clone)]
+    struct PixelShader;
+
+    impl Shader for PixelShader {
+        type Varyings = V3;
+
+        fn prepare(&mut self, _ctx: &RenderContext) {}
+
+        fn vertex(&mut self, ctx: &RenderContext, _index: usize, vertex: &Vertex) -> (V4, V3) {
+            let position = ctx.viewport * ctx.projection * ctx.modelview;
+            (
+                position * vertex.position.extend(1.),
+                vertex.position * 0.01,
+            )
+        }
+
+        fn fragment(&mut self, _ctx: &RenderContext, frag: &Fragment<V3>) -> Option<V4> {
+            let c = frag.varyings;
+            Some(v4(0.2 + c.x, 0.2 + c.y, 0.5 + c.z, 1.))
+        }
+    }
+
+    // The pixels a draw covered on a cleared renderer
+    fn coverage(renderer: &Renderer) -> Vec<(u32, u32)> {
+        let buffer = renderer.display_buffer();
+        let mut pixels = Vec::new();
+        for y in 0..buffer.height() {
+            for x in 0..buffer.width() {
+                if buffer.get(x, y) != v3(0., 0., 0.) {
+                    pixels.push((x, y));
+                }
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn shared_edges_are_drawn_once() {
+        // The diagonal and the square's sides all run through pixel centers
+        let square = "v 1 1 0\nv 9 1 0\nv 9 9 0\nv 1 9 0\n";
+        let faces = ["f 1 2 3\n", "f 1 3 4\n"];
+        let mut renderer = Renderer::new(12, 12, 1);
+
+        let mut covered = Vec::new();
+        for (i, face) in faces.iter().enumerate() {
+            let single = parse("fill", &format!("{}{}", square, face)).unwrap();
+            renderer.clear(v3(0., 0., 0.));
+            renderer.render(&mut PixelShader, &single);
+            covered.push(coverage(&renderer));
+            assert!(!covered[i].is_empty());
+        }
+        assert!(covered[0].iter().all(|p| !covered[1].contains(p)));
+        assert_eq!(covered[0].len() + covered[1].len(), 64);
+
+        let model = parse("fill", &format!("{}{}", square, faces.concat())).unwrap();
+        renderer.clear(v3(0., 0., 0.));
+        renderer.render(&mut PixelShader, &model);
+        let mut both = coverage(&renderer);
+        let mut union: Vec<_> = covered.concat();
+        both.sort_unstable();
+        union.sort_unstable();
+        assert_eq!(both, union);
+    }
+
+    #[test]
+    fn levels_past_the_mip_chain() {
+        let texture = Texture::new(4, 4, 0.5);
+        assert_eq!(texture.get_level(1, 0, 0), 0.5);
+        assert_eq!(texture.level_size(3), (4, 4));
+
+        let mut texture = Texture::new(8, 4, 0.5);
+        texture.build_mips();
+        let last = texture.levels() - 1;
+        assert_eq!(texture.level_size(last + 2), texture.level_size(last));
+        assert_eq!(texture.get_lod(0.5, 0.5, 10.), 0.5);
+    }
+
+    #[test]
+    fn samplers_keep_the_texture_config() {
+        let mut texture = Texture::new(4, 1, 0.);
+        for x in 0..4 {
+            texture.set(x, 0, x as f64);
+        }
+        texture.sampler(SamplerConfig::new(AddressMode::Repeat, 0.));
+
+        assert_eq!(texture.get_f(-0.125, 0.5), 3.);
+        assert_eq!(BilinearSampler::new(texture.clone()).get_f(-0.125, 0.5), 3.);
+        assert_eq!(TrilinearSampler::new(texture).get_f(-0.125, 0.5), 3.);
+    }
+
+    #[test]
+    fn shared_vertices_are_shaded_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        #[derive(Clone)]
+        struct CountingShader(Arc<AtomicUsize>);
+
+        impl Shader for CountingShader {
+            type Varyings = V3;
+
+            fn prepare(&mut self, ctx: &RenderContext) {
+                PixelShader.prepare(ctx)
+            }
+
+            fn vertex(&mut self, ctx: &RenderContext, index: usize, vertex: &Vertex) -> (V4, V3) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                PixelShader.vertex(ctx, index, vertex)
+            }
+
+            fn fragment(&mut self, ctx: &RenderContext, frag: &Fragment<V3>) -> Option<V4> {
+                PixelShader.fragment(ctx, frag)
+            }
+        }
+
+        let source = "v 1 1 0\nv 9 1 0\nv 9 9 0\nv 1 9 0\nvn 0 0 1\n\
+                      f 1//1 2//1 3//1\nf 1//1 3//1 4//1\n";
+        let model = parse("shaded", source).unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut renderer = Renderer::new(12, 12, 1);
+        renderer.render(&mut CountingShader(Arc::clone(&calls)), &model);
+
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn threads_render_the_same_image() {
+        // Overlapping triangles at different depths spread over many tiles
+        let mut source = String::new();
+        let mut seed = 12345u64;
+        let mut random = || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as f64 / (1u64 << 31) as f64
+        };
+        for i in 0..300 {
+            let (x, y, z) = (random() * 300., random() * 200., random() - 0.5);
+            for _ in 0..3 {
+                let (dx, dy) = (random() * 80. - 40., random() * 80. - 40.);
+                source.push_str(&format!("v {} {} {}\n", x + dx, y + dy, z));
+            }
+            source.push_str(&format!("f {} {} {}\n", i * 3 + 1, i * 3 + 2, i * 3 + 3));
+        }
+        let model = parse("threads", &source).unwrap();
+
+        let render = |threads: usize| {
+            let mut renderer = Renderer::new(280, 190, 4);
+            renderer.threads(threads);
+            renderer.clear(v3(0., 0., 0.));
+            renderer.render(&mut PixelShader, &model);
+            renderer
+        };
+        let (single, threaded) = (render(1), render(8));
+        assert!(single.z_buffer().get(140, 95) != f64::MIN);
+
+        for y in 0..190 {
+            for x in 0..280 {
+                let (a, b) = (
+                    single.display_buffer().get(x, y),
+                    threaded.display_buffer().get(x, y),
+                );
+                assert_eq!(
+                    (a.x.to_bits(), a.y.to_bits(), a.z.to_bits()),
+                    (b.x.to_bits(), b.y.to_bits(), b.z.to_bits()),
+                    "color at {} {}",
+                    x,
+                    y
+                );
+                let (a, b) = (single.z_buffer().get(x, y), threaded.z_buffer().get(x, y));
+                assert_eq!(a.to_bits(), b.to_bits(), "depth at {} {}", x, y);
+            }
+        }
+    }
+}
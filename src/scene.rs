@@ -0,0 +1,739 @@
+use crate::camera::{Camera, Projection};
+use crate::error::{Error, Result};
+use crate::graph::Node;
+use crate::light::{Attenuation, Light};
+use crate::model::Model;
+use crate::renderer::{BlendState, Renderer, Surface};
+use crate::shaders::{DefaultShader, DepthShader, SolidShader};
+use crate::shadow::{CascadeParams, CascadedShadowMap, CubeShadowMap, ShadowMap, ShadowParams};
+use crate::ssao::{ambient_occlusion, SsaoParams};
+use crate::{cgmath, v3, InnerSpace, SquareMatrix, M4, V3};
+
+use serde::Deserialize;
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub struct Scene {
+    pub width: u32,
+    pub height: u32,
+    pub clear: V3,
+    pub cameras: HashMap<String, Camera>,
+    pub active_camera: String,
+    pub lights: Vec<SceneLight>,
+    pub ssao: Option<SsaoParams>,
+    pub root: Node<Appearance>,
+}
+
+// Spin is the angle in radians the light turns about the world y axis every
+// time the scene advances a frame.
+#[derive(Copy, Clone, Debug)]
+pub struct SceneLight {
+    pub light: Light,
+    pub shadow: Option<LightShadow>,
+    pub spin: f64,
+}
+
+impl SceneLight {
+    // Turns the light and its shadow camera by its spin
+    pub fn advance(&mut self) {
+        if self.spin == 0. {
+            return;
+        }
+
+        let rotation = M4::from_angle_y(cgmath::Rad(self.spin));
+        self.light = self.light.transform(rotation);
+        if let Some(LightShadow::Map { camera, .. }) = &mut self.shadow {
+            let turn = |v: V3| (rotation * v.extend(0.)).truncate();
+            camera.eye = camera.target + turn(camera.eye - camera.target);
+            camera.up = turn(camera.up);
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum LightShadow {
+    // Directional lights render a shadow map from the camera
+    Map {
+        camera: Camera,
+        params: ShadowParams,
+    },
+    // Directional lights can instead fit a cascade to each slice of the
+    // active camera's view
+    Cascaded {
+        cascades: CascadeParams,
+        params: ShadowParams,
+    },
+    // Point lights render a cube shadow map around them between near and far
+    Cube {
+        near: f64,
+        far: f64,
+        params: ShadowParams,
+    },
+}
+
+// Every placement of a model in the graph that is drawn with the same shader
+// and blend state
+pub struct Object {
+    pub model: Arc<Model>,
+    pub instances: Vec<M4>,
+    pub shader: ObjectShader,
+    pub blend: BlendState,
+}
+
+impl Object {
+    pub fn is_opaque(&self) -> bool {
+        self.blend == BlendState::REPLACE
+    }
+}
+
+// How the model of a node in the scene's graph is drawn
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Appearance {
+    pub shader: ObjectShader,
+    pub blend: BlendState,
+}
+
+impl Default for Appearance {
+    fn default() -> Appearance {
+        Appearance {
+            shader: ObjectShader::Default,
+            blend: BlendState::REPLACE,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObjectShader {
+    #[default]
+    Default,
+    Solid,
+}
+
+impl Scene {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Scene> {
+        let path = path.as_ref();
+        let text = ::std::fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        let file: SceneFile =
+            toml::from_str(&text).map_err(|e| Error::scene(path, e.to_string()))?;
+
+        // Files named by the scene are relative to it
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        if file.width == 0 || file.height == 0 {
+            return Err(Error::scene(path, "scene size must be positive"));
+        }
+        let aspect = file.width as f64 / file.height as f64;
+
+        let cameras: HashMap<String, Camera> = file
+            .cameras
+            .iter()
+            .map(|c| (c.name.clone(), c.camera(aspect)))
+            .collect();
+        let active_camera = match file.camera {
+            Some(name) if cameras.contains_key(&name) => name,
+            Some(name) => return Err(Error::scene(path, format!("unknown camera `{}`", name))),
+            None => match file.cameras.first() {
+                Some(camera) => camera.name.clone(),
+                None => return Err(Error::scene(path, "scene has no cameras")),
+            },
+        };
+
+        if file.lights.is_empty() {
+            return Err(Error::scene(path, "scene has no lights"));
+        }
+        let lights = file
+            .lights
+            .iter()
+            .map(|l| l.light(path))
+            .collect::<Result<_>>()?;
+
+        let mut models = HashMap::new();
+        let mut root = Node::default();
+        for object in &file.objects {
+            root.add(object.node(path, dir, &mut models)?);
+        }
+
+        Ok(Scene {
+            width: file.width,
+            height: file.height,
+            clear: v3(file.clear[0], file.clear[1], file.clear[2]),
+            cameras,
+            active_camera,
+            lights,
+            ssao: file.ssao,
+            root,
+        })
+    }
+
+    pub fn camera(&self) -> &Camera {
+        &self.cameras[&self.active_camera]
+    }
+
+    // Moves the scene on to its next frame
+    pub fn advance(&mut self) {
+        for light in &mut self.lights {
+            light.advance();
+        }
+    }
+
+    // Flattens the graph into world space instances, every node with the
+    // same model and shader is drawn by a single object.
+    pub fn objects(&self) -> Vec<Object> {
+        let mut objects: Vec<Object> = Vec::new();
+        self.root.traverse(M4::identity(), &mut |node, world| {
+            let model = match &node.model {
+                Some(model) => model,
+                None => return,
+            };
+
+            let existing = objects.iter_mut().find(|o| {
+                let appearance = &node.payload;
+                Arc::ptr_eq(&o.model, model)
+                    && o.shader == appearance.shader
+                    && o.blend == appearance.blend
+            });
+            match existing {
+                Some(object) => object.instances.push(world),
+                None => objects.push(Object {
+                    model: Arc::clone(model),
+                    instances: vec![world],
+                    shader: node.payload.shader,
+                    blend: node.payload.blend,
+                }),
+            }
+        });
+
+        objects
+    }
+
+    // A shadow pass from the first directional light with a shadow, six more
+    // for each point light with a shadow and the shaded pass from the active
+    // camera, all over the whole of the renderer's buffers. Only opaque
+    // objects cast shadows, blended instances are drawn after them from back
+    // to front without writing depth. Ambient occlusion, when enabled, darkens
+    // the result last.
+    pub fn render(&self, renderer: &mut Renderer) {
+        let (width, height) = {
+            let buffer = renderer.display_buffer();
+            (buffer.width(), buffer.height())
+        };
+        renderer.viewport(0., 0., width as f64, height as f64);
+        let objects = self.objects();
+
+        let (opaque, blended): (Vec<&Object>, Vec<&Object>) =
+            objects.iter().partition(|o| o.is_opaque());
+
+        let mut default = DefaultShader::new();
+        let mut solid = SolidShader::new();
+
+        let mut shader = DepthShader::new();
+        let mut draw_casters = |renderer: &mut Renderer| {
+            for object in &opaque {
+                renderer.render_instanced(&mut shader, &object.model, &object.instances);
+            }
+        };
+
+        let mut directional = false;
+        for (index, light) in self.lights.iter().enumerate() {
+            match (light.light, light.shadow) {
+                (_, Some(LightShadow::Map { camera, params })) if !directional => {
+                    let shadow = ShadowMap::render(renderer, &camera, &mut draw_casters);
+                    default.shadow(index, shadow);
+                    default.shadow_params(params);
+                    directional = true;
+                }
+                (
+                    Light::Directional { direction, .. },
+                    Some(LightShadow::Cascaded { cascades, params }),
+                ) if !directional => {
+                    let cascaded = CascadedShadowMap::render(
+                        renderer,
+                        self.camera(),
+                        direction,
+                        &cascades,
+                        &mut draw_casters,
+                    );
+                    default.cascaded_shadow(index, cascaded);
+                    default.shadow_params(params);
+                    directional = true;
+                }
+                (Light::Point { position, .. }, Some(LightShadow::Cube { near, far, params })) => {
+                    let cube =
+                        CubeShadowMap::render(renderer, position, near, far, &mut draw_casters);
+                    default.cube_shadow(index, cube, params);
+                }
+                _ => (),
+            }
+        }
+
+        let lights: Vec<Light> = self.lights.iter().map(|l| l.light).collect();
+        renderer.lights(&lights);
+        renderer.camera(self.camera());
+
+        renderer.clear(self.clear);
+        for object in &opaque {
+            match object.shader {
+                ObjectShader::Default => {
+                    renderer.render_instanced(&mut default, &object.model, &object.instances)
+                }
+                ObjectShader::Solid => {
+                    renderer.render_instanced(&mut solid, &object.model, &object.instances)
+                }
+            }
+        }
+
+        // Instances are sorted by the view depth of their origin
+        let view = renderer.modelview;
+        let mut instances: Vec<(&Object, M4)> = blended
+            .iter()
+            .flat_map(|&o| o.instances.iter().map(move |&m| (o, m)))
+            .collect();
+        instances.sort_by(|a, b| {
+            let depth = |m: M4| (view * m).w.z;
+            depth(a.1)
+                .partial_cmp(&depth(b.1))
+                .unwrap_or(Ordering::Equal)
+        });
+
+        renderer.depth_write(false);
+        for (object, instance) in instances {
+            renderer.blend(object.blend);
+            match object.shader {
+                ObjectShader::Default => {
+                    renderer.render_instanced(&mut default, &object.model, &[instance])
+                }
+                ObjectShader::Solid => {
+                    renderer.render_instanced(&mut solid, &object.model, &[instance])
+                }
+            }
+        }
+        renderer.blend(BlendState::REPLACE);
+        renderer.depth_write(true);
+
+        if let Some(params) = &self.ssao {
+            let occlusion = ambient_occlusion(renderer, params);
+            renderer.modulate(&occlusion);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    #[serde(default = "default_size")]
+    width: u32,
+    #[serde(default = "default_size")]
+    height: u32,
+    #[serde(default)]
+    clear: [f64; 3],
+    camera: Option<String>,
+    #[serde(default)]
+    cameras: Vec<CameraFile>,
+    #[serde(default)]
+    lights: Vec<LightFile>,
+    #[serde(default)]
+    objects: Vec<ObjectFile>,
+    ssao: Option<SsaoParams>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraFile {
+    name: String,
+    eye: [f64; 3],
+    #[serde(default)]
+    target: [f64; 3],
+    #[serde(default = "default_up")]
+    up: [f64; 3],
+    projection: ProjectionFile,
+}
+
+impl CameraFile {
+    fn camera(&self, aspect: f64) -> Camera {
+        Camera::new(
+            vector(self.eye),
+            vector(self.target),
+            vector(self.up),
+            self.projection.projection(aspect),
+        )
+    }
+}
+
+// Angles are in degrees, the aspect ratio follows the scene size unless given
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum ProjectionFile {
+    Perspective {
+        fov_y: f64,
+        aspect: Option<f64>,
+        near: f64,
+        far: f64,
+    },
+    Orthographic {
+        left: f64,
+        right: f64,
+        bottom: f64,
+        top: f64,
+        near: f64,
+        far: f64,
+    },
+}
+
+impl ProjectionFile {
+    fn projection(&self, scene_aspect: f64) -> Projection {
+        match *self {
+            ProjectionFile::Perspective {
+                fov_y,
+                aspect,
+                near,
+                far,
+            } => Projection::Perspective {
+                fov_y: fov_y.to_radians(),
+                aspect: aspect.unwrap_or(scene_aspect),
+                near,
+                far,
+            },
+            ProjectionFile::Orthographic {
+                left,
+                right,
+                bottom,
+                top,
+                near,
+                far,
+            } => Projection::Orthographic {
+                left,
+                right,
+                bottom,
+                top,
+                near,
+                far,
+            },
+        }
+    }
+}
+
+// Directional lights point towards the light, spot lights the way they shine.
+// Attenuation is [constant, linear, quadratic] and the cone angles are in
+// degrees, inner defaults to outer for a hard edged spot. Spin is in degrees
+// per frame.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LightFile {
+    #[serde(default, rename = "type")]
+    kind: LightKind,
+    direction: Option<[f64; 3]>,
+    position: Option<[f64; 3]>,
+    #[serde(default = "default_color")]
+    color: [f64; 3],
+    #[serde(default = "default_intensity")]
+    intensity: f64,
+    #[serde(default = "default_attenuation")]
+    attenuation: [f64; 3],
+    inner: Option<f64>,
+    outer: Option<f64>,
+    #[serde(default)]
+    spin: f64,
+    shadow: Option<ShadowFile>,
+}
+
+#[derive(Copy, Clone, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LightKind {
+    #[default]
+    Directional,
+    Point,
+    Spot,
+}
+
+impl LightFile {
+    fn light(&self, path: &Path) -> Result<SceneLight> {
+        let field = |value: Option<[f64; 3]>, name: &str| match value {
+            Some(v) => Ok(vector(v)),
+            None => Err(Error::scene(path, format!("light needs a {}", name))),
+        };
+        let color = vector(self.color);
+        let intensity = self.intensity;
+        let [constant, linear, quadratic] = self.attenuation;
+        let attenuation = Attenuation {
+            constant,
+            linear,
+            quadratic,
+        };
+
+        let light = match self.kind {
+            LightKind::Directional => Light::Directional {
+                direction: field(self.direction, "direction")?,
+                color,
+                intensity,
+            },
+            LightKind::Point => Light::Point {
+                position: field(self.position, "position")?,
+                color,
+                intensity,
+                attenuation,
+            },
+            LightKind::Spot => {
+                let outer = match self.outer {
+                    Some(outer) => outer,
+                    None => return Err(Error::scene(path, "spot light needs an outer angle")),
+                };
+                Light::Spot {
+                    position: field(self.position, "position")?,
+                    direction: field(self.direction, "direction")?,
+                    color,
+                    intensity,
+                    attenuation,
+                    inner: self.inner.unwrap_or(outer).to_radians(),
+                    outer: outer.to_radians(),
+                }
+            }
+        };
+
+        if let Some(field) = self.shadow.as_ref().and_then(|s| s.unknown.keys().min()) {
+            return Err(Error::scene(
+                path,
+                format!("unknown shadow field `{}`", field),
+            ));
+        }
+        let shadow = match (light, &self.shadow) {
+            (Light::Directional { direction, .. }, Some(shadow)) => Some(shadow.map(direction)),
+            (Light::Point { .. }, Some(shadow)) => Some(shadow.cube()),
+            (Light::Spot { .. }, Some(_)) => {
+                return Err(Error::scene(path, "spot lights don't cast shadows"))
+            }
+            (_, None) => None,
+        };
+
+        Ok(SceneLight {
+            light,
+            shadow,
+            spin: self.spin.to_radians(),
+        })
+    }
+}
+
+impl ShadowFile {
+    fn map(&self, direction: V3) -> LightShadow {
+        if let Some(cascades) = self.cascades {
+            return LightShadow::Cascaded {
+                cascades,
+                params: self.params,
+            };
+        }
+
+        let target = vector(self.target);
+        let camera = Camera::new(
+            target + direction.normalize() * self.distance,
+            target,
+            vector(self.up),
+            Projection::Orthographic {
+                left: self.left,
+                right: self.right,
+                bottom: self.bottom,
+                top: self.top,
+                near: self.near,
+                far: self.far,
+            },
+        );
+
+        LightShadow::Map {
+            camera,
+            params: self.params,
+        }
+    }
+
+    fn cube(&self) -> LightShadow {
+        LightShadow::Cube {
+            near: self.near,
+            far: self.far,
+            params: self.params,
+        }
+    }
+}
+
+// The box the shadow map covers, looking at target from distance along the
+// light, and how it is filtered. Biases are in shadow map depth units, lit
+// and shadowed scale the light reaching lit and fully shadowed points. Point
+// lights only use near and far of the box, cascades replace it with boxes
+// fitted to the camera. The ShadowParams fields share the table, so unknown
+// fields can't be denied by serde and are collected to be reported instead.
+#[derive(Deserialize)]
+#[serde(default)]
+struct ShadowFile {
+    target: [f64; 3],
+    up: [f64; 3],
+    distance: f64,
+    left: f64,
+    right: f64,
+    bottom: f64,
+    top: f64,
+    near: f64,
+    far: f64,
+    cascades: Option<CascadeParams>,
+    #[serde(flatten)]
+    params: ShadowParams,
+    #[serde(flatten)]
+    unknown: HashMap<String, toml::Value>,
+}
+
+impl Default for ShadowFile {
+    fn default() -> ShadowFile {
+        ShadowFile {
+            target: [0., 0., 0.],
+            up: default_up(),
+            distance: 3.,
+            left: -2.,
+            right: 2.,
+            bottom: -2.,
+            top: 2.,
+            near: 1.,
+            far: 5.,
+            cascades: None,
+            params: ShadowParams::default(),
+            unknown: HashMap::new(),
+        }
+    }
+}
+
+// Objects without a model group their children
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ObjectFile {
+    name: Option<String>,
+    model: Option<PathBuf>,
+    textures: Option<TexturesFile>,
+    #[serde(default)]
+    shader: ObjectShader,
+    #[serde(default)]
+    blend: BlendFile,
+    #[serde(default)]
+    translation: [f64; 3],
+    #[serde(default)]
+    rotation: [f64; 3],
+    #[serde(default = "default_scale")]
+    scale: [f64; 3],
+    #[serde(default)]
+    children: Vec<ObjectFile>,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TexturesFile {
+    diffuse: PathBuf,
+    specular: PathBuf,
+    normal: PathBuf,
+}
+
+#[derive(Copy, Clone, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BlendFile {
+    #[default]
+    Replace,
+    Alpha,
+    Additive,
+}
+
+impl BlendFile {
+    fn state(self) -> BlendState {
+        match self {
+            BlendFile::Replace => BlendState::REPLACE,
+            BlendFile::Alpha => BlendState::ALPHA,
+            BlendFile::Additive => BlendState::ADDITIVE,
+        }
+    }
+}
+
+type ModelKey = (PathBuf, Option<TexturesFile>);
+
+impl ObjectFile {
+    fn node(
+        &self,
+        path: &Path,
+        dir: &Path,
+        models: &mut HashMap<ModelKey, Arc<Model>>,
+    ) -> Result<Node<Appearance>> {
+        let mut node = Node::new(self.transform());
+        node.name = self.name.clone();
+        node.payload = Appearance {
+            shader: self.shader,
+            blend: self.blend.state(),
+        };
+
+        match (&self.model, &self.textures) {
+            (Some(model), _) => {
+                let key = (model.clone(), self.textures.clone());
+                let model = match models.get(&key) {
+                    Some(model) => Arc::clone(model),
+                    None => {
+                        let model = Arc::new(self.load(model, dir)?);
+                        models.insert(key, Arc::clone(&model));
+                        model
+                    }
+                };
+                node.model = Some(model);
+            }
+            (None, Some(_)) => return Err(Error::scene(path, "textures given without a model")),
+            (None, None) => (),
+        }
+
+        for child in &self.children {
+            node.add(child.node(path, dir, models)?);
+        }
+
+        Ok(node)
+    }
+
+    // Without textures the model's own mtl materials are used
+    fn load(&self, model: &Path, dir: &Path) -> Result<Model> {
+        match &self.textures {
+            Some(textures) => Model::with_textures(
+                dir.join(model),
+                dir.join(&textures.diffuse),
+                dir.join(&textures.specular),
+                dir.join(&textures.normal),
+            ),
+            None => Model::load(dir.join(model)),
+        }
+    }
+
+    // Scaled, then rotated about x, y and z in that order, then translated
+    fn transform(&self) -> M4 {
+        let [x, y, z] = self.rotation;
+        M4::from_translation(vector(self.translation))
+            * M4::from_angle_z(cgmath::Deg(z))
+            * M4::from_angle_y(cgmath::Deg(y))
+            * M4::from_angle_x(cgmath::Deg(x))
+            * M4::from_nonuniform_scale(self.scale[0], self.scale[1], self.scale[2])
+    }
+}
+
+fn vector(v: [f64; 3]) -> V3 {
+    v3(v[0], v[1], v[2])
+}
+
+fn default_size() -> u32 {
+    1024
+}
+
+fn default_up() -> [f64; 3] {
+    [0., 1., 0.]
+}
+
+fn default_color() -> [f64; 3] {
+    [1., 1., 1.]
+}
+
+fn default_intensity() -> f64 {
+    1.
+}
+
+fn default_attenuation() -> [f64; 3] {
+    [1., 0., 0.]
+}
+
+fn default_scale() -> [f64; 3] {
+    [1., 1., 1.]
+}
@@ -1,137 +1,244 @@
-use crate::{v3, InnerSpace, Matrix, SquareMatrix, M3, M4, V3, V4};
+use crate::{v3, v4, ElementWise, InnerSpace, Matrix, SquareMatrix, Zero, M3, M4, V2, V3, V4};
 
-use crate::model::Face;
-use crate::renderer::{matrix_transform, BilinearSampler, RenderContext, Shader, Surface, Texture};
+use crate::light::Light;
+use crate::model::Vertex;
+use crate::renderer::{matrix_transform, Fragment, RenderContext, Shader};
+use crate::shadow::{CascadedShadowMap, CubeShadowMap, ShadowMap, ShadowParams};
 
+use std::sync::Arc;
+
+// Diffuse lighting from every light in the context, evaluated per vertex
+#[derive(Clone)]
 pub struct SolidShader {
-    light_dir: V3,
-    intensity: V3,
+    lights: Vec<Light>,
     transform: M4,
 }
 
 impl SolidShader {
-    pub fn new(light_dir: V3) -> SolidShader {
+    pub fn new() -> SolidShader {
         SolidShader {
-            light_dir: light_dir.normalize(),
-            intensity: V3::unit_z(),
+            lights: Vec::new(),
             transform: M4::identity(),
         }
     }
 }
 
+impl Default for SolidShader {
+    fn default() -> SolidShader {
+        SolidShader::new()
+    }
+}
+
 impl Shader for SolidShader {
+    type Varyings = V3;
+
     fn prepare(&mut self, ctx: &RenderContext) {
         self.transform = ctx.viewport * ctx.projection * ctx.modelview;
+        self.lights = view_lights(ctx);
     }
 
-    fn vertex(&mut self, _ctx: &RenderContext, face: &Face, vert: usize) -> V4 {
-        self.intensity[vert] = face.norms[vert].dot(self.light_dir);
-        self.transform * face.verts[vert].extend(1.0)
+    fn vertex(&mut self, ctx: &RenderContext, _index: usize, vertex: &Vertex) -> (V4, V3) {
+        let norm = (ctx.normal_matrix * vertex.normal).normalize();
+        let position = matrix_transform(vertex.position, ctx.modelview);
+        let c = self.lights.iter().fold(V3::zero(), |c, light| {
+            let (l, radiance) = light.illuminate(position);
+            c + radiance * norm.dot(l).max(0.)
+        });
+        (self.transform * vertex.position.extend(1.0), c)
     }
 
-    fn fragment(&mut self, _ctx: &RenderContext, coords: V3) -> Option<V3> {
-        let intensity = self.intensity.dot(coords).max(0.0);
-        let c = v3(1., 1., 1.) * intensity;
-        Some(c)
+    fn fragment(&mut self, _ctx: &RenderContext, frag: &Fragment<V3>) -> Option<V4> {
+        let c = frag.varyings;
+        Some(v3(c.x.min(1.), c.y.min(1.), c.z.min(1.)).extend(1.))
     }
 }
 
+// Diffuse and specular lighting summed over every light in the context. One
+// light can be shadowed by a shadow map or cascaded shadow map and any number
+// by cube shadow maps.
+#[derive(Clone)]
 pub struct DefaultShader {
-    light_dir: V3,
-    light_depth: BilinearSampler<Texture<f64>>,
-    light_matrix: M4,
+    lights: Vec<Light>,
+    shadow: Option<(usize, Arc<ShadowMap>)>,
+    cascades: Option<(usize, Arc<CascadedShadowMap>)>,
+    shadow_params: ShadowParams,
+    cube_shadows: Vec<(usize, Arc<CubeShadowMap>, ShadowParams)>,
+    shadow_matrix: M4,
+    inverse_view: M4,
     transform: M4,
-    pm: M4,
-    pm_t: M4,
-    uv: M3,
-    norm: M3,
-    ndc_coords: M3,
-    shadow_coords: M3,
 }
 
 impl DefaultShader {
-    pub fn new(light_dir: V3, light_depth: Texture<f64>, light_matrix: M4) -> DefaultShader {
+    pub fn new() -> DefaultShader {
         DefaultShader {
-            light_dir: light_dir.normalize(),
-            light_depth: BilinearSampler::new(light_depth),
-            light_matrix,
+            lights: Vec::new(),
+            shadow: None,
+            cascades: None,
+            shadow_params: ShadowParams::default(),
+            cube_shadows: Vec::new(),
+            shadow_matrix: M4::identity(),
+            inverse_view: M4::identity(),
             transform: M4::identity(),
-            pm: M4::identity(),
-            pm_t: M4::identity(),
-            uv: M3::identity(),
-            norm: M3::identity(),
-            ndc_coords: M3::identity(),
-            shadow_coords: M3::identity(),
         }
     }
+
+    // The shadow map darkens the light at that index of the context's lights
+    pub fn shadow(&mut self, light: usize, shadow: ShadowMap) {
+        self.shadow = Some((light, Arc::new(shadow)));
+    }
+
+    pub fn cascaded_shadow(&mut self, light: usize, shadow: CascadedShadowMap) {
+        self.cascades = Some((light, Arc::new(shadow)));
+    }
+
+    pub fn shadow_params(&mut self, params: ShadowParams) {
+        self.shadow_params = params;
+    }
+
+    pub fn cube_shadow(&mut self, light: usize, shadow: CubeShadowMap, params: ShadowParams) {
+        self.cube_shadows.push((light, Arc::new(shadow), params));
+    }
+
+    // How much of the light at that index reaches the fragment
+    fn visibility(&self, index: usize, frag: &Fragment<<Self as Shader>::Varyings>) -> f64 {
+        let (_, _, position, shadow_c) = frag.varyings;
+        let mut intensity = match &self.shadow {
+            Some((light, shadow)) if *light == index => {
+                shadow.intensity(&self.shadow_params, shadow_c, frag.ddx.3, frag.ddy.3)
+            }
+            _ => 1.,
+        };
+
+        let world = |v: V3, w: f64| (self.inverse_view * v.extend(w)).truncate();
+        if let Some((light, shadow)) = &self.cascades {
+            if *light == index {
+                intensity *= shadow.intensity(
+                    &self.shadow_params,
+                    -position.z,
+                    world(position, 1.),
+                    world(frag.ddx.2, 0.),
+                    world(frag.ddy.2, 0.),
+                );
+            }
+        }
+
+        for (light, shadow, params) in &self.cube_shadows {
+            if *light == index {
+                intensity *= shadow.intensity(
+                    params,
+                    world(position, 1.),
+                    world(frag.ddx.2, 0.),
+                    world(frag.ddy.2, 0.),
+                );
+            }
+        }
+
+        intensity
+    }
+}
+
+impl Default for DefaultShader {
+    fn default() -> DefaultShader {
+        DefaultShader::new()
+    }
 }
 
 impl Shader for DefaultShader {
+    // uv, view space normal and position and shadow map position
+    type Varyings = (V2, V3, V3, V3);
+
     fn prepare(&mut self, ctx: &RenderContext) {
         self.transform = ctx.viewport * ctx.projection * ctx.modelview;
-        self.pm = ctx.projection * ctx.modelview;
-        self.pm_t = self.pm.transpose().invert().unwrap();
+        if let Some((_, shadow)) = &self.shadow {
+            self.shadow_matrix = shadow.matrix * ctx.model_matrix;
+        }
+        self.inverse_view = ctx.view.invert().unwrap_or_else(M4::identity);
+        self.lights = view_lights(ctx);
     }
 
-    fn vertex(&mut self, _ctx: &RenderContext, face: &Face, vert: usize) -> V4 {
-        self.uv[vert] = face.texs[vert].extend(1.);
-        self.norm[vert] = (self.pm_t * face.norms[vert].extend(0.)).truncate();
-        self.shadow_coords[vert] = matrix_transform(face.verts[vert], self.light_matrix);
+    fn vertex(
+        &mut self,
+        ctx: &RenderContext,
+        _index: usize,
+        vertex: &Vertex,
+    ) -> (V4, Self::Varyings) {
+        let norm = ctx.normal_matrix * vertex.normal;
+        let position = matrix_transform(vertex.position, ctx.modelview);
+        let shadow_coords = matrix_transform(vertex.position, self.shadow_matrix);
 
-        let next_vert = self.transform * face.verts[vert].extend(1.);
-        self.ndc_coords[vert] = (next_vert / next_vert.w).truncate();
-
-        next_vert
+        (
+            self.transform * vertex.position.extend(1.),
+            (vertex.uv, norm, position, shadow_coords),
+        )
     }
 
-    fn fragment(&mut self, ctx: &RenderContext, coords: V3) -> Option<V3> {
-        let norm = (self.norm * coords).normalize();
-        let uv = (self.uv * coords).truncate();
+    fn fragment(&mut self, ctx: &RenderContext, frag: &Fragment<Self::Varyings>) -> Option<V4> {
+        let face = &frag.face;
+        let (uv, norm, position, _) = frag.varyings;
+        let (ddx, ddy) = (frag.ddx.0, frag.ddy.0);
+        let norm = norm.normalize();
 
-        let shadow_c = self.shadow_coords * coords;
-        let (x, y) = (
-            shadow_c.x / (self.light_depth.width() - 1) as f64,
-            shadow_c.y / (self.light_depth.height() - 1) as f64,
-        );
+        let material = ctx.material(face.material);
 
-        let shadow = if self.light_depth.get_f(x, y) < shadow_c.z + 0.02 {
-            1.0
-        } else {
-            0.3
-        };
-
-        let a = M3::from_cols(
-            self.ndc_coords[1] - self.ndc_coords[0],
-            self.ndc_coords[2] - self.ndc_coords[0],
-            norm,
-        )
-        .transpose();
+        // Without uvs there is no tangent space to apply the normal map in
+        let n = if face.has_texs && material.bump_map.is_some() {
+            let view_coords = face
+                .verts
+                .map(|v| matrix_transform(v.position, ctx.modelview));
+            let uvs = face.verts.map(|v| v.uv);
+            let a = M3::from_cols(
+                view_coords[1] - view_coords[0],
+                view_coords[2] - view_coords[0],
+                norm,
+            )
+            .transpose();
 
-        let ai = a.invert().unwrap();
-        let i = ai * v3(self.uv[1].x - self.uv[0].x, self.uv[2].x - self.uv[0].x, 0.);
-        let j = ai * v3(self.uv[1].y - self.uv[0].y, self.uv[2].y - self.uv[0].y, 0.);
+            let ai = a.invert().unwrap();
+            let i = ai * v3(uvs[1].x - uvs[0].x, uvs[2].x - uvs[0].x, 0.);
+            let j = ai * v3(uvs[1].y - uvs[0].y, uvs[2].y - uvs[0].y, 0.);
 
-        let b = M3::from_cols(i.normalize(), j.normalize(), norm);
+            let b = M3::from_cols(i.normalize(), j.normalize(), norm);
 
-        let n = (b * ctx.model.normal(uv)).normalize();
+            (b * material.normal(uv, ddx, ddy)).normalize()
+        } else {
+            norm
+        };
 
-        let l = matrix_transform(self.light_dir, self.pm).normalize();
-        let r = ((n * n.dot(l * 2.)) - l).normalize();
-        let diffuse = n.dot(l).max(0.0);
-        let specular = r.z.max(0.0).powf(ctx.model.specular(uv));
-        let c = ctx.model.diffuse(uv);
+        let c = material.diffuse(uv, ddx, ddy);
         if c.w <= 0.0 {
             return None;
         }
-        let mut c = c.truncate() * (diffuse + 0.6 * specular) * shadow;
+        let shininess = material.shininess(uv, ddx, ddy);
+        let specular_color = material.specular(uv, ddx, ddy);
+
+        let mut lit = V3::zero();
+        for (index, light) in self.lights.iter().enumerate() {
+            let (l, radiance) = light.illuminate(position);
+            let diffuse = n.dot(l);
+            if diffuse <= 0. {
+                continue;
+            }
+
+            let r = ((n * n.dot(l * 2.)) - l).normalize();
+            let specular = r.z.max(0.0).powf(shininess);
+            let shadow = self.visibility(index, frag);
+
+            lit += (specular_color * specular)
+                .add_element_wise(diffuse)
+                .mul_element_wise(radiance)
+                * shadow;
+        }
+
+        let mut rgb = c.truncate().mul_element_wise(lit);
         for i in 0..3 {
-            c[i] = (c[i] + 0.02).min(1.);
+            rgb[i] = (rgb[i] + material.ambient[i]).min(1.);
         }
 
-        Some(c)
+        Some(rgb.extend(c.w))
     }
 }
 
+#[derive(Clone)]
 pub struct DepthShader {
     transform: M4,
 }
@@ -144,16 +251,29 @@ impl DepthShader {
     }
 }
 
+impl Default for DepthShader {
+    fn default() -> DepthShader {
+        DepthShader::new()
+    }
+}
+
 impl Shader for DepthShader {
+    type Varyings = ();
+
     fn prepare(&mut self, ctx: &RenderContext) {
         self.transform = ctx.viewport * ctx.projection * ctx.modelview;
     }
 
-    fn vertex(&mut self, _ctx: &RenderContext, face: &Face, vert: usize) -> V4 {
-        self.transform * face.verts[vert].extend(1.)
+    fn vertex(&mut self, _ctx: &RenderContext, _index: usize, vertex: &Vertex) -> (V4, ()) {
+        (self.transform * vertex.position.extend(1.), ())
     }
 
-    fn fragment(&mut self, _ctx: &RenderContext, _coords: V3) -> Option<V3> {
-        Some(v3(0., 0., 0.))
+    fn fragment(&mut self, _ctx: &RenderContext, _frag: &Fragment<()>) -> Option<V4> {
+        Some(v4(0., 0., 0., 1.))
     }
 }
+
+// Lights are given in world space, shading happens in view space
+fn view_lights(ctx: &RenderContext) -> Vec<Light> {
+    ctx.lights.iter().map(|l| l.transform(ctx.view)).collect()
+}
@@ -0,0 +1,551 @@
+use crate::camera::{Camera, Projection};
+use crate::renderer::{lookat, AddressMode, Renderer, SamplerConfig, Surface, Texture};
+use crate::{v2, v3, InnerSpace, SquareMatrix, M4, V2, V3};
+
+use serde::Deserialize;
+
+// Taps on the unit disk, filters take as many of them as they need
+const POISSON_DISK: [(f64, f64); 16] = [
+    (-0.94201624, -0.39906216),
+    (0.94558609, -0.76890725),
+    (-0.094184101, -0.92938870),
+    (0.34495938, 0.29387760),
+    (-0.91588581, 0.45771432),
+    (-0.81544232, -0.87912464),
+    (-0.38277543, 0.27676845),
+    (0.97484398, 0.75648379),
+    (0.44323325, -0.97511554),
+    (0.53742981, -0.47373420),
+    (-0.26496911, -0.41893023),
+    (0.79197514, 0.19090188),
+    (-0.24188840, 0.99706507),
+    (-0.81409955, 0.91437590),
+    (0.19984126, 0.78641367),
+    (0.14383161, -0.14100790),
+];
+
+// Radii are in shadow map texels. Every filter other than Hard compares
+// against the four texels around each tap and weights them bilinearly.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum ShadowFilter {
+    Hard,
+    Pcf {
+        radius: u32,
+    },
+    Poisson {
+        radius: f64,
+        #[serde(default = "default_samples")]
+        samples: usize,
+    },
+    // The penumbra grows with the distance between the receiver and the
+    // average blocker, light_size is its width in texels for a blocker at
+    // the near plane over a receiver at the far plane.
+    Pcss {
+        light_size: f64,
+        #[serde(default = "default_samples")]
+        samples: usize,
+    },
+}
+
+fn default_samples() -> usize {
+    POISSON_DISK.len()
+}
+
+// Biases are in the depth units of the shadow map. The slope scaled bias is
+// multiplied by how much the receiver's depth changes per texel and the sum
+// of both is clamped to max_bias.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ShadowParams {
+    pub filter: ShadowFilter,
+    pub bias: f64,
+    pub slope_bias: f64,
+    pub max_bias: f64,
+    pub lit: f64,
+    pub shadowed: f64,
+}
+
+impl Default for ShadowParams {
+    fn default() -> ShadowParams {
+        ShadowParams {
+            filter: ShadowFilter::Pcf { radius: 1 },
+            bias: 0.002,
+            slope_bias: 2.,
+            max_bias: 0.05,
+            lit: 1.,
+            shadowed: 0.3,
+        }
+    }
+}
+
+// The depth buffer of a pass rendered from a light, matrix takes world space
+// to the pixels and depth of that pass.
+#[derive(Clone)]
+pub struct ShadowMap {
+    depth: Texture<f64>,
+    pub matrix: M4,
+}
+
+impl ShadowMap {
+    pub fn new(mut depth: Texture<f64>, matrix: M4) -> ShadowMap {
+        // Nothing outside of the shadow map casts a shadow
+        depth.sampler(SamplerConfig::new(AddressMode::ClampToBorder, f64::MIN));
+
+        ShadowMap { depth, matrix }
+    }
+
+    // Renders over the whole of the renderer's buffers from the camera,
+    // `draw` is called after the camera is set and the buffers cleared.
+    pub fn render<F: FnOnce(&mut Renderer)>(
+        renderer: &mut Renderer,
+        camera: &Camera,
+        draw: F,
+    ) -> ShadowMap {
+        renderer.camera(camera);
+        renderer.clear(v3(0., 0., 0.));
+        draw(renderer);
+
+        ShadowMap::capture(renderer)
+    }
+
+    // The renderer's depth buffer as it was left by the last pass
+    pub fn capture(renderer: &Renderer) -> ShadowMap {
+        let matrix = renderer.viewport * renderer.projection * renderer.modelview;
+        ShadowMap::new(renderer.z_buffer().clone(), matrix)
+    }
+
+    pub fn depth(&self) -> &Texture<f64> {
+        &self.depth
+    }
+
+    // Scales the depth the map holds and the depth its matrix gives
+    fn scale_depth(&mut self, scale: f64) {
+        for y in 0..self.depth.height() {
+            for x in 0..self.depth.width() {
+                let depth = self.depth.get(x, y);
+                if depth != f64::MIN {
+                    self.depth.set(x, y, depth * scale);
+                }
+            }
+        }
+        self.matrix = M4::from_nonuniform_scale(1., 1., scale) * self.matrix;
+    }
+
+    // The light intensity at a receiver, p is its position in the shadow map
+    // and ddx and ddy are the screen space derivatives of p. Taps away from p
+    // are compared against the depth the receiver's plane has under them so
+    // wide kernels don't shadow the receiver with itself.
+    pub fn intensity(&self, params: &ShadowParams, p: V3, ddx: V3, ddy: V3) -> f64 {
+        let slope = depth_slope(ddx, ddy);
+        let bias = (params.bias + params.slope_bias * slope.x.abs().max(slope.y.abs()))
+            .min(params.max_bias);
+        let receiver = Receiver {
+            at: v2(p.x, p.y),
+            z: p.z + bias,
+            slope,
+        };
+
+        let visibility = match params.filter {
+            ShadowFilter::Hard => {
+                let (x, y) = (p.x.round(), p.y.round());
+                self.compare(x as i64, y as i64, receiver.z)
+            }
+            ShadowFilter::Pcf { radius } => {
+                let radius = radius as i64;
+                let mut sum = 0.;
+                for y in -radius..radius + 1 {
+                    for x in -radius..radius + 1 {
+                        sum += self.pcf(&receiver, v2(x as f64, y as f64));
+                    }
+                }
+                sum / ((2 * radius + 1) * (2 * radius + 1)) as f64
+            }
+            ShadowFilter::Poisson { radius, samples } => self.poisson(&receiver, radius, samples),
+            ShadowFilter::Pcss {
+                light_size,
+                samples,
+            } => match self.blocker_depth(&receiver, light_size, samples) {
+                Some(blocker) => {
+                    let penumbra = (light_size * (blocker - receiver.z)).max(1.);
+                    self.poisson(&receiver, penumbra, samples)
+                }
+                None => 1.,
+            },
+        };
+
+        params.shadowed + (params.lit - params.shadowed) * visibility
+    }
+
+    // Like intensity with p and its derivatives in world space
+    pub fn world_intensity(&self, params: &ShadowParams, p: V3, ddx: V3, ddy: V3) -> f64 {
+        let at = face_coords(p, self.matrix);
+        let ddx = face_coords(p + ddx, self.matrix) - at;
+        let ddy = face_coords(p + ddy, self.matrix) - at;
+
+        self.intensity(params, at, ddx, ddy)
+    }
+
+    // 1 if the texel doesn't hide z from the light, depth is greater closer
+    // to the light.
+    fn compare(&self, x: i64, y: i64, z: f64) -> f64 {
+        if self.texel(x, y) < z {
+            1.
+        } else {
+            0.
+        }
+    }
+
+    fn texel(&self, x: i64, y: i64) -> f64 {
+        self.depth.get_f(
+            (x as f64 + 0.5) / self.depth.width() as f64,
+            (y as f64 + 0.5) / self.depth.height() as f64,
+        )
+    }
+
+    fn pcf(&self, receiver: &Receiver, offset: V2) -> f64 {
+        let (at, z) = (receiver.at + offset, receiver.depth(offset));
+        let (x0, y0) = (at.x.floor(), at.y.floor());
+        let (tx, ty) = (at.x - x0, at.y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let r0 = self.compare(x0, y0, z) * (1. - tx) + self.compare(x0 + 1, y0, z) * tx;
+        let r1 = self.compare(x0, y0 + 1, z) * (1. - tx) + self.compare(x0 + 1, y0 + 1, z) * tx;
+
+        r0 * (1. - ty) + r1 * ty
+    }
+
+    fn poisson(&self, receiver: &Receiver, radius: f64, samples: usize) -> f64 {
+        let taps = poisson_taps(samples);
+        let sum: f64 = taps
+            .iter()
+            .map(|&(x, y)| self.pcf(receiver, v2(x, y) * radius))
+            .sum();
+
+        sum / taps.len() as f64
+    }
+
+    // The average depth of the texels hiding the receiver within the radius
+    fn blocker_depth(&self, receiver: &Receiver, radius: f64, samples: usize) -> Option<f64> {
+        let (sum, count) = poisson_taps(samples)
+            .iter()
+            .map(|&(x, y)| v2(x, y) * radius)
+            .filter_map(|offset| {
+                let at = receiver.at + offset;
+                let depth = self.texel(at.x.round() as i64, at.y.round() as i64);
+                if depth >= receiver.depth(offset) {
+                    Some(depth)
+                } else {
+                    None
+                }
+            })
+            .fold((0., 0), |(sum, count), depth| (sum + depth, count + 1));
+
+        if count > 0 {
+            Some(sum / count as f64)
+        } else {
+            None
+        }
+    }
+}
+
+// Looking down +x, -x, +y, -y, +z and -z
+fn cube_faces() -> [(V3, V3); 6] {
+    [
+        (v3(1., 0., 0.), v3(0., -1., 0.)),
+        (v3(-1., 0., 0.), v3(0., -1., 0.)),
+        (v3(0., 1., 0.), v3(0., 0., 1.)),
+        (v3(0., -1., 0.), v3(0., 0., -1.)),
+        (v3(0., 0., 1.), v3(0., -1., 0.)),
+        (v3(0., 0., -1.), v3(0., -1., 0.)),
+    ]
+}
+
+// Texels every cube face extends past its 90 degree square so filter taps
+// near its edges don't fall off of it
+const CUBE_MARGIN: f64 = 8.;
+
+// Six perspective shadow maps around a point light, looked up by the
+// direction from the light.
+#[derive(Clone)]
+pub struct CubeShadowMap {
+    position: V3,
+    faces: Vec<ShadowMap>,
+}
+
+impl CubeShadowMap {
+    // Renders each face over the whole of the renderer's buffers, `draw` is
+    // called once per face after its camera is set and the buffers cleared.
+    // A face holds n (f - d) / (f - n) at distance d before the divide, so it
+    // is divided by near to hold what a directional map between the same
+    // planes would and ShadowParams mean the same for both.
+    pub fn render<F: FnMut(&mut Renderer)>(
+        renderer: &mut Renderer,
+        position: V3,
+        near: f64,
+        far: f64,
+        mut draw: F,
+    ) -> CubeShadowMap {
+        let (width, height) = {
+            let buffer = renderer.z_buffer();
+            (buffer.width() as f64, buffer.height() as f64)
+        };
+        let aspect = width / height;
+        let half = width.min(height) / 2.;
+        let extent = half / (half - CUBE_MARGIN).max(1.) / aspect.min(1.);
+        let projection = Projection::Perspective {
+            fov_y: 2. * extent.atan(),
+            aspect,
+            near,
+            far,
+        };
+
+        let faces = cube_faces()
+            .iter()
+            .map(|&(direction, up)| {
+                let camera = Camera::new(position, position + direction, up, projection);
+                let mut face = ShadowMap::render(renderer, &camera, &mut draw);
+                face.scale_depth(1. / near);
+                face
+            })
+            .collect();
+
+        CubeShadowMap { position, faces }
+    }
+
+    pub fn position(&self) -> V3 {
+        self.position
+    }
+
+    // The face looking along the major axis of the direction
+    pub fn face(&self, direction: V3) -> &ShadowMap {
+        let a = v3(direction.x.abs(), direction.y.abs(), direction.z.abs());
+        let (axis, value) = if a.x >= a.y && a.x >= a.z {
+            (0, direction.x)
+        } else if a.y >= a.z {
+            (1, direction.y)
+        } else {
+            (2, direction.z)
+        };
+
+        &self.faces[axis * 2 + if value < 0. { 1 } else { 0 }]
+    }
+
+    // Like ShadowMap::intensity with p and its derivatives in world space
+    pub fn intensity(&self, params: &ShadowParams, p: V3, ddx: V3, ddy: V3) -> f64 {
+        self.face(p - self.position)
+            .world_intensity(params, p, ddx, ddy)
+    }
+}
+
+// The rasterizer interpolates depth before the perspective divide, so only x
+// and y are divided by w to match what the faces hold.
+fn face_coords(p: V3, m: M4) -> V3 {
+    let p = m * p.extend(1.);
+    v3(p.x / p.w, p.y / p.w, p.z)
+}
+
+// How the camera's view is split between cascades. Split moves the slice
+// boundaries from evenly spaced at 0 to logarithmically spaced at 1, each
+// slice blends into the next over its last blend fraction and casters up to
+// caster_distance towards the light from a slice are kept.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CascadeParams {
+    pub count: usize,
+    pub split: f64,
+    pub distance: f64,
+    pub caster_distance: f64,
+    pub blend: f64,
+}
+
+impl Default for CascadeParams {
+    fn default() -> CascadeParams {
+        CascadeParams {
+            count: 4,
+            split: 0.75,
+            distance: 20.,
+            caster_distance: 3.,
+            blend: 0.1,
+        }
+    }
+}
+
+// Shadow maps for consecutive slices of a camera's view, each covering the
+// view out to its far distance.
+#[derive(Clone)]
+pub struct CascadedShadowMap {
+    cascades: Vec<(f64, ShadowMap)>,
+    blend: f64,
+}
+
+impl CascadedShadowMap {
+    // Renders a slice over the whole of the renderer's buffers for each
+    // cascade, `draw` is called once per slice after its camera is set and
+    // the buffers cleared. direction points towards the light.
+    pub fn render<F: FnMut(&mut Renderer)>(
+        renderer: &mut Renderer,
+        camera: &Camera,
+        direction: V3,
+        params: &CascadeParams,
+        mut draw: F,
+    ) -> CascadedShadowMap {
+        let (width, height) = {
+            let buffer = renderer.z_buffer();
+            (buffer.width() as f64, buffer.height() as f64)
+        };
+        let direction = direction.normalize();
+        let up = if direction.y.abs() > 0.99 {
+            v3(0., 0., 1.)
+        } else {
+            v3(0., 1., 0.)
+        };
+        // Light space with its origin fixed so slice centers can be snapped
+        // to whole texels, which keeps the edges of shadows from crawling as
+        // the camera moves.
+        let light_space = lookat(direction, v3(0., 0., 0.), up);
+        let world_space = light_space.invert().unwrap_or_else(M4::identity);
+
+        let splits = cascade_splits(camera, params);
+        let cascades = splits
+            .windows(2)
+            .map(|slice| {
+                let corners = camera.frustum_corners(slice[0], slice[1]);
+                let center = corners.iter().fold(v3(0., 0., 0.), |sum, &c| sum + c) / 8.;
+                let radius = corners
+                    .iter()
+                    .map(|&c| (c - center).magnitude())
+                    .fold(0., f64::max);
+
+                let (texel_x, texel_y) = (2. * radius / width, 2. * radius / height);
+                let c = (light_space * center.extend(1.)).truncate();
+                let c = v3(
+                    (c.x / texel_x).floor() * texel_x,
+                    (c.y / texel_y).floor() * texel_y,
+                    c.z,
+                );
+                let center = (world_space * c.extend(1.)).truncate();
+
+                let distance = radius + params.caster_distance;
+                let camera = Camera::new(
+                    center + direction * distance,
+                    center,
+                    up,
+                    Projection::Orthographic {
+                        left: -radius,
+                        right: radius,
+                        bottom: -radius,
+                        top: radius,
+                        near: 0.,
+                        far: distance + radius,
+                    },
+                );
+                (slice[1], ShadowMap::render(renderer, &camera, &mut draw))
+            })
+            .collect();
+
+        CascadedShadowMap {
+            cascades,
+            blend: params.blend,
+        }
+    }
+
+    // The far distance of each cascade
+    pub fn splits(&self) -> Vec<f64> {
+        self.cascades.iter().map(|(far, _)| *far).collect()
+    }
+
+    // Like ShadowMap::intensity with p and its derivatives in world space,
+    // view_depth is the distance of p in front of the camera. Past the last
+    // cascade nothing is shadowed.
+    pub fn intensity(
+        &self,
+        params: &ShadowParams,
+        view_depth: f64,
+        p: V3,
+        ddx: V3,
+        ddy: V3,
+    ) -> f64 {
+        let index = match self.cascades.iter().position(|(far, _)| view_depth < *far) {
+            Some(index) => index,
+            None => return params.lit,
+        };
+
+        let sample = |map: &ShadowMap| map.world_intensity(params, p, ddx, ddy);
+
+        let (far, map) = &self.cascades[index];
+        let near = if index > 0 {
+            self.cascades[index - 1].0
+        } else {
+            0.
+        };
+        let intensity = sample(map);
+
+        // The last cascade fades out instead of into the next one
+        let fade = (far - near) * self.blend;
+        let t = (view_depth - (far - fade)) / fade.max(1e-9);
+        if t <= 0. {
+            return intensity;
+        }
+        let next = match self.cascades.get(index + 1) {
+            Some((_, next)) => sample(next),
+            None => params.lit,
+        };
+
+        intensity + (next - intensity) * t.min(1.)
+    }
+}
+
+// The near plane of the camera followed by the far distance of every slice,
+// mixing evenly and logarithmically spaced boundaries. Logarithmic spacing
+// needs a near plane in front of the camera, orthographic cameras with near
+// at or behind it are split evenly.
+fn cascade_splits(camera: &Camera, params: &CascadeParams) -> Vec<f64> {
+    let (near, far) = camera.projection.near_far();
+    let far = far.min(params.distance).max(near);
+    let count = params.count.max(1);
+
+    (0..count + 1)
+        .map(|i| {
+            let f = i as f64 / count as f64;
+            let uniform = near + (far - near) * f;
+            let log = if near > 0. {
+                near * (far / near).powf(f)
+            } else {
+                uniform
+            };
+            uniform + (log - uniform) * params.split
+        })
+        .collect()
+}
+
+fn poisson_taps(samples: usize) -> &'static [(f64, f64)] {
+    &POISSON_DISK[..samples.max(1).min(POISSON_DISK.len())]
+}
+
+// A point being shaded, biased depth included, and the plane it lies on
+struct Receiver {
+    at: V2,
+    z: f64,
+    slope: V2,
+}
+
+impl Receiver {
+    fn depth(&self, offset: V2) -> f64 {
+        self.z + self.slope.dot(offset)
+    }
+}
+
+// How much the receiver's depth changes per shadow map texel in x and y,
+// found by solving for it from the screen space derivatives of its position.
+fn depth_slope(ddx: V3, ddy: V3) -> V2 {
+    let det = ddx.x * ddy.y - ddy.x * ddx.y;
+    if det.abs() < 1e-12 {
+        return v2(0., 0.);
+    }
+
+    v2(
+        (ddx.z * ddy.y - ddy.z * ddx.y) / det,
+        (ddy.z * ddx.x - ddx.z * ddy.x) / det,
+    )
+}
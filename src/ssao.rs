@@ -0,0 +1,241 @@
+use crate::renderer::{Renderer, Surface, Texture};
+use crate::{v3, InnerSpace, Matrix, SquareMatrix, M3, M4, V3};
+
+use serde::Deserialize;
+
+// Radius and bias are in world units. Each pixel takes samples points in
+// the hemisphere around its normal, strength scales how much occluded points
+// darken it and the blur averages occlusion over blur_radius pixels of the
+// same surface, sharpness sets how quickly depth differences stop it.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SsaoParams {
+    pub radius: f64,
+    pub samples: usize,
+    pub bias: f64,
+    pub strength: f64,
+    pub blur_radius: u32,
+    pub sharpness: f64,
+}
+
+impl Default for SsaoParams {
+    fn default() -> SsaoParams {
+        SsaoParams {
+            radius: 0.5,
+            samples: 16,
+            bias: 0.02,
+            strength: 1.,
+            blur_radius: 2,
+            sharpness: 8.,
+        }
+    }
+}
+
+// The ambient occlusion of every pixel in the renderer's depth buffer from
+// the projection it was last drawn with, 1 where nothing is occluded or
+// nothing was drawn. It can be multiplied into the display buffer with
+// Renderer::modulate or sampled by the shaders of a later pass.
+pub fn ambient_occlusion(renderer: &Renderer, params: &SsaoParams) -> Texture<f64> {
+    let depth = renderer.z_buffer();
+    let (width, height) = (depth.width(), depth.height());
+    let projection = renderer.viewport * renderer.projection;
+
+    let surface = ViewSurface::new(depth, projection);
+    let kernel = hemisphere_kernel(params.samples.max(1));
+
+    let mut occlusion = Texture::new(width, height, 1.);
+    for y in 0..height {
+        for x in 0..width {
+            let (p, toward) = match surface.point(x as i64, y as i64) {
+                Some(point) => point,
+                None => continue,
+            };
+            let n = match surface.normal(x as i64, y as i64, p, toward) {
+                Some(n) => n,
+                None => continue,
+            };
+
+            // Every pixel in a 4x4 block turns the kernel by a different
+            // angle, the blur averages the banding this would leave away.
+            let angle = (((x % 4) * 4 + y % 4) as f64 / 16.) * ::std::f64::consts::PI * 2.;
+            let basis = tangent_basis(n, angle);
+
+            let mut occluded = 0.;
+            for &k in &kernel {
+                let s = p + basis * k * params.radius;
+                let clip = projection * s.extend(1.);
+                if clip.w <= 0. {
+                    continue;
+                }
+
+                let (sx, sy) = ((clip.x / clip.w).round(), (clip.y / clip.w).round());
+                let q = match surface.point(sx as i64, sy as i64) {
+                    Some((q, _)) => q,
+                    None => continue,
+                };
+
+                // Occluders further than the radius away fade out so
+                // foreground edges don't darken the background behind them
+                if (q - s).dot(toward) > params.bias {
+                    let range = params.radius / (p - q).magnitude().max(1e-9);
+                    occluded += range.min(1.);
+                }
+            }
+
+            let ao = 1. - params.strength * occluded / kernel.len() as f64;
+            occlusion.set(x, y, ao.max(0.));
+        }
+    }
+
+    blur(&occlusion, &surface, params)
+}
+
+// Positions in view space reconstructed from a depth buffer. The rasterizer
+// stores depth before the perspective divide, so a pixel's screen position
+// and depth give three linear equations in its view space position.
+struct ViewSurface {
+    width: i64,
+    height: i64,
+    points: Vec<Option<(V3, V3)>>,
+}
+
+impl ViewSurface {
+    fn new(depth: &Texture<f64>, projection: M4) -> ViewSurface {
+        let (width, height) = (depth.width(), depth.height());
+        let m = projection.transpose();
+
+        let mut points = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let depth = depth.get(x, y);
+                if depth == f64::MIN {
+                    points.push(None);
+                    continue;
+                }
+
+                let rows = [m.x - m.w * x as f64, m.y - m.w * y as f64, m.z];
+                let a = M3::from_cols(rows[0].truncate(), rows[1].truncate(), rows[2].truncate())
+                    .transpose()
+                    .invert();
+                points.push(a.map(|a| {
+                    let p = a * v3(-rows[0].w, -rows[1].w, depth - rows[2].w);
+                    // Greater depth is closer to the camera
+                    (p, (a * v3(0., 0., 1.)).normalize())
+                }));
+            }
+        }
+
+        ViewSurface {
+            width: width as i64,
+            height: height as i64,
+            points,
+        }
+    }
+
+    // The view space position at the pixel and the unit vector from it
+    // towards the camera
+    fn point(&self, x: i64, y: i64) -> Option<(V3, V3)> {
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return None;
+        }
+        self.points[(y * self.width + x) as usize]
+    }
+
+    // The normal of the surface at the pixel from its neighbours, taking the
+    // side with the smaller depth difference so edges don't bend it.
+    fn normal(&self, x: i64, y: i64, p: V3, toward: V3) -> Option<V3> {
+        let side = |a: Option<(V3, V3)>, b: Option<(V3, V3)>| match (a, b) {
+            (Some((a, _)), Some((b, _))) => {
+                if (a - p).dot(toward).abs() < (b - p).dot(toward).abs() {
+                    Some(a - p)
+                } else {
+                    Some(p - b)
+                }
+            }
+            (Some((a, _)), None) => Some(a - p),
+            (None, Some((b, _))) => Some(p - b),
+            (None, None) => None,
+        };
+
+        let dx = side(self.point(x + 1, y), self.point(x - 1, y))?;
+        let dy = side(self.point(x, y + 1), self.point(x, y - 1))?;
+        let n = dx.cross(dy);
+        if n.magnitude2() == 0. {
+            return None;
+        }
+
+        let n = n.normalize();
+        Some(if n.dot(toward) < 0. { -n } else { n })
+    }
+}
+
+// Points in the hemisphere around +z, spread over it by the golden angle and
+// packed closer to the center so nearby occluders count for more.
+fn hemisphere_kernel(samples: usize) -> Vec<V3> {
+    let golden_angle = ::std::f64::consts::PI * (3. - 5f64.sqrt());
+    (0..samples)
+        .map(|i| {
+            let t = (i as f64 + 0.5) / samples as f64;
+            let z = 1. - t;
+            let r = (1. - z * z).sqrt();
+            let angle = golden_angle * i as f64;
+            let scale = 0.1 + 0.9 * t * t;
+
+            v3(r * angle.cos(), r * angle.sin(), z.max(0.1)).normalize() * scale
+        })
+        .collect()
+}
+
+// Columns are a tangent and bitangent turned by angle about n, and n
+fn tangent_basis(n: V3, angle: f64) -> M3 {
+    let helper = if n.x.abs() < 0.9 {
+        v3(1., 0., 0.)
+    } else {
+        v3(0., 1., 0.)
+    };
+    let t = helper.cross(n).normalize();
+    let b = n.cross(t);
+    let t = t * angle.cos() + b * angle.sin();
+
+    M3::from_cols(t, n.cross(t), n)
+}
+
+// Averages occlusion with neighbours weighted by distance and by how far
+// their surface is from this pixel's along the view direction.
+fn blur(occlusion: &Texture<f64>, surface: &ViewSurface, params: &SsaoParams) -> Texture<f64> {
+    let (width, height) = (occlusion.width(), occlusion.height());
+    let radius = params.blur_radius as i64;
+    let sigma = (radius as f64 / 2.).max(0.5);
+
+    let mut blurred = Texture::new(width, height, 1.);
+    for y in 0..height as i64 {
+        for x in 0..width as i64 {
+            let (p, toward) = match surface.point(x, y) {
+                Some(point) => point,
+                None => continue,
+            };
+
+            let (mut sum, mut weights) = (0., 0.);
+            for oy in -radius..radius + 1 {
+                for ox in -radius..radius + 1 {
+                    let q = match surface.point(x + ox, y + oy) {
+                        Some((q, _)) => q,
+                        None => continue,
+                    };
+
+                    let distance = (ox * ox + oy * oy) as f64;
+                    let difference = (q - p).dot(toward) / params.radius;
+                    let weight = (-distance / (2. * sigma * sigma)).exp()
+                        * (-params.sharpness * difference * difference).exp();
+
+                    sum += occlusion.get((x + ox) as u32, (y + oy) as u32) * weight;
+                    weights += weight;
+                }
+            }
+
+            blurred.set(x as u32, y as u32, sum / weights);
+        }
+    }
+
+    blurred
+}
@@ -113,12 +113,12 @@ impl Window {
     fn process_events(&mut self) {
         let closed = self.closed.get_mut();
         self.event_loop.run_return(|event, _window, control_flow| {
-            match event {
-                Event::WindowEvent {
-                    event: WindowEvent::CloseRequested,
-                    ..
-                } => *closed = true,
-                _ => (),
+            if let Event::WindowEvent {
+                event: WindowEvent::CloseRequested,
+                ..
+            } = event
+            {
+                *closed = true;
             }
             *control_flow = ControlFlow::Exit;
         })
@@ -140,7 +140,7 @@ impl Window {
         target
             .draw(
                 &self.vertex_buffer,
-                &self.indicies,
+                self.indicies,
                 &self.program,
                 &uniforms,
                 &Default::default(),
@@ -158,21 +158,21 @@ impl Window {
 
 use crate::renderer::{Color as RColor, Surface as RSurface, Texture as RTexture};
 
-impl<'a, 'b, T> Into<RawImage2d<'a, (u8, u8, u8, u8)>> for &'b RTexture<T>
+impl<'a, T> From<&RTexture<T>> for RawImage2d<'a, (u8, u8, u8, u8)>
 where
     T: Into<RColor> + Copy,
 {
-    fn into(self) -> RawImage2d<'a, (u8, u8, u8, u8)> {
+    fn from(texture: &RTexture<T>) -> RawImage2d<'a, (u8, u8, u8, u8)> {
         let mut data = Vec::new();
-        for y in 0..self.height() {
-            for x in 0..self.width() {
-                let c = self.get(x, self.height() - y - 1).into().to_linear();
+        for y in 0..texture.height() {
+            for x in 0..texture.width() {
+                let c = texture.get(x, texture.height() - y - 1).into().to_linear();
                 data.push((c.r(), c.g(), c.b(), c.a()));
             }
         }
         RawImage2d {
-            width: self.width(),
-            height: self.height(),
+            width: texture.width(),
+            height: texture.height(),
             format: ClientFormat::U8U8U8U8,
             data: data.into(),
         }